@@ -0,0 +1,1202 @@
+use crate::client::{EndpointResolver, Source, StsClient};
+use crate::error::REDACTED;
+use crate::identity::IdentityTokenProvider;
+use crate::spawn::{self, Spawn};
+use crate::{
+    AssumeRoleParams, ClockSkewPolicy, EnvironmentIds, InFlightPolicy, Inner, PostLoad,
+    RateLimiter, STSCredentialsProvider, SessionTokenPolicy, ShortLifetimePolicy, LATENCY_SAMPLES,
+};
+use arc_swap::ArcSwapOption;
+use aws_endpoint::ResolveAwsEndpoint;
+use aws_sdk_sts::output::AssumeRoleOutput;
+use aws_sdk_sts::AppName;
+use aws_smithy_async::rt::sleep::AsyncSleep;
+#[cfg(feature = "rt-tokio")]
+use aws_smithy_async::rt::sleep::TokioSleep;
+use aws_smithy_client::erase::DynConnector;
+use aws_types::credentials::CredentialsError;
+use aws_types::os_shim_internal::TimeSource;
+use aws_types::Credentials;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::env;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::RwLock;
+
+/// Builder for [`STSCredentialsProvider`].
+///
+/// Obtained through [`STSCredentialsProvider::builder`]. The only mandatory parameter is the ARN
+/// of the role to assume, every other option has a default.
+///
+/// String options accept anything convertible into a `String`, and have `set_` counterparts taking
+/// an `Option`:
+///
+/// ```
+/// use aws_sdk_sts_caching_provider::STSCredentialsProvider;
+///
+/// let session_name: String = format!("worker-{}", std::process::id());
+/// let external_id: Option<String> = std::env::var("EXTERNAL_ID").ok();
+/// let provider = STSCredentialsProvider::builder("arn:aws:iam::123456789012:role/example")
+///     .session_name(session_name)
+///     .set_external_id(external_id)
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Debug)]
+pub struct STSCredentialsProviderBuilder {
+    params: AssumeRoleParams,
+    cache_timeout: Duration,
+    refresh_ahead: Option<Duration>,
+    delivery_cushion: Duration,
+    connector: Option<DynConnector>,
+    source: Source,
+    /// Names of the setters that chose the source, to detect conflicts
+    source_setters: Vec<&'static str>,
+    fallbacks: Vec<FallbackRole>,
+    short_lifetime: ShortLifetimePolicy,
+    clock_skew: ClockSkewPolicy,
+    sleep: Option<Arc<dyn AsyncSleep>>,
+    spawner: Option<Arc<dyn Spawn>>,
+    time: TimeSource,
+    clear_cache_on_error: bool,
+    unique_session_names: bool,
+    environments: HashMap<String, EnvironmentIds>,
+    selected_environment: Option<String>,
+    post_load: Option<PostLoad>,
+    endpoint_resolver: Option<EndpointResolver>,
+    session_token: SessionTokenPolicy,
+    lock_timeout: Option<Duration>,
+    in_flight: InFlightPolicy,
+    max_lifetime_calls: Option<u64>,
+    rate_limiter: Option<RateLimiter>,
+    provider_name: &'static str,
+    app_name: Option<String>,
+    passthrough: bool,
+    scoped_idle_ttl: Duration,
+    scoped_max_entries: usize,
+    identity_tokens: Option<Arc<dyn IdentityTokenProvider>>,
+}
+
+impl STSCredentialsProviderBuilder {
+    pub(crate) fn new(role_arn: &str) -> Self {
+        Self {
+            params: AssumeRoleParams {
+                role_arn: role_arn.to_string(),
+                external_id: None,
+                source_identity: None,
+                session_name: None,
+                session_duration: None,
+                tags: Vec::new(),
+                policy: None,
+            },
+            cache_timeout: Duration::from_secs(60),
+            refresh_ahead: None,
+            delivery_cushion: Duration::ZERO,
+            connector: None,
+            source: Source::Environment,
+            source_setters: Vec::new(),
+            fallbacks: Vec::new(),
+            short_lifetime: ShortLifetimePolicy::default(),
+            clock_skew: ClockSkewPolicy::default(),
+            sleep: None,
+            spawner: spawn::default_spawner(),
+            time: TimeSource::default(),
+            clear_cache_on_error: true,
+            unique_session_names: false,
+            environments: HashMap::new(),
+            selected_environment: None,
+            post_load: None,
+            endpoint_resolver: None,
+            session_token: SessionTokenPolicy::default(),
+            lock_timeout: None,
+            in_flight: InFlightPolicy::default(),
+            max_lifetime_calls: None,
+            rate_limiter: None,
+            provider_name: DEFAULT_PROVIDER_NAME,
+            app_name: None,
+            passthrough: false,
+            scoped_idle_ttl: DEFAULT_SCOPED_IDLE_TTL,
+            scoped_max_entries: DEFAULT_SCOPED_MAX_ENTRIES,
+            identity_tokens: None,
+        }
+    }
+
+    /// Returns a builder configured from environment variables.
+    ///
+    /// See [`STSCredentialsProvider::from_env`] for the variables that are read.
+    pub(crate) fn from_env() -> Result<Self, BuildError> {
+        let role_arn = env_var(ENV_ROLE_ARN)?.ok_or(BuildError::MissingEnvVar(ENV_ROLE_ARN))?;
+        let mut builder = Self::new(&role_arn);
+        if let Some(external_id) = env_var(ENV_EXTERNAL_ID)? {
+            builder = builder.external_id(external_id);
+        }
+        if let Some(session_name) = env_var(ENV_SESSION_NAME)? {
+            builder = builder.session_name(session_name);
+        }
+        if let Some(session_duration) = parsed_env_var(ENV_SESSION_DURATION)? {
+            builder = builder.session_duration(session_duration);
+        }
+        if let Some(cache_timeout) = parsed_env_var(ENV_CACHE_TIMEOUT)? {
+            builder = builder.cache_timeout(Duration::from_secs(cache_timeout));
+        }
+        Ok(builder)
+    }
+
+    /// Sets the external id sent with the `AssumeRole` call.
+    pub fn external_id(self, external_id: impl Into<String>) -> Self {
+        self.set_external_id(Some(external_id.into()))
+    }
+
+    /// Sets or unsets the external id sent with the `AssumeRole` call.
+    pub fn set_external_id(mut self, external_id: Option<String>) -> Self {
+        self.params.external_id = external_id;
+        self
+    }
+
+    /// Sets the source identity sent with the `AssumeRole` call.
+    pub fn source_identity(self, source_identity: impl Into<String>) -> Self {
+        self.set_source_identity(Some(source_identity.into()))
+    }
+
+    /// Sets or unsets the source identity sent with the `AssumeRole` call.
+    pub fn set_source_identity(mut self, source_identity: Option<String>) -> Self {
+        self.params.source_identity = source_identity;
+        self
+    }
+
+    /// Sets the role session name sent with the `AssumeRole` call.
+    pub fn session_name(self, session_name: impl Into<String>) -> Self {
+        self.set_session_name(Some(session_name.into()))
+    }
+
+    /// Sets or unsets the role session name sent with the `AssumeRole` call.
+    pub fn set_session_name(mut self, session_name: Option<String>) -> Self {
+        self.params.session_name = session_name;
+        self
+    }
+
+    /// Sets whether a random suffix is appended to the session name on each `AssumeRole` call.
+    ///
+    /// This keeps the names readable while making each session distinguishable in CloudTrail,
+    /// e.g. `my-service-7f3a09c1`. The name is shortened if needed to fit the 64 characters
+    /// accepted by STS. It has no effect if no session name is set, and it also applies to the
+    /// fallback roles. The generated name is part of the assumed-role ARN.
+    ///
+    /// Disabled by default.
+    pub fn unique_session_names(mut self, unique_session_names: bool) -> Self {
+        self.unique_session_names = unique_session_names;
+        self
+    }
+
+    /// Declares the external id and source identity to use for an environment.
+    ///
+    /// This is meant for deployments targeting several accounts that expect different
+    /// identifiers. Providers for the other environments are obtained with
+    /// [`STSCredentialsProvider::for_environment`], and the one used by the built provider is
+    /// chosen with [`select_environment`](Self::select_environment).
+    pub fn environment(mut self, environment: impl Into<String>, ids: EnvironmentIds) -> Self {
+        self.environments.insert(environment.into(), ids);
+        self
+    }
+
+    /// Uses the external id and source identity of a declared environment.
+    ///
+    /// They are used as the external id and the source identity of the built provider, even if
+    /// unset for the environment, so these can't also be set directly.
+    pub fn select_environment(mut self, environment: impl Into<String>) -> Self {
+        self.selected_environment = Some(environment.into());
+        self
+    }
+
+    /// Sets the requested session duration, in seconds.
+    pub fn session_duration(mut self, session_duration: i32) -> Self {
+        self.params.session_duration = Some(session_duration);
+        self
+    }
+
+    /// Sets the session tags sent with the `AssumeRole` call, replacing any previously set.
+    ///
+    /// The tags are sent sorted by key, so that CloudTrail entries are stable.
+    pub fn tags(mut self, tags: HashMap<String, String>) -> Self {
+        self.params.tags = tags.into_iter().collect();
+        self
+    }
+
+    /// Adds a session tag sent with the `AssumeRole` call.
+    ///
+    /// Adding the same key twice makes [`build`](Self::build) fail.
+    pub fn tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.params.tags.push((key.into(), value.into()));
+        self
+    }
+
+    /// Sets how long before their expiry the credentials are considered stale.
+    ///
+    /// Once stale, reads block until fresh credentials have been obtained from STS.
+    /// Defaults to 60 seconds.
+    pub fn cache_timeout(mut self, cache_timeout: Duration) -> Self {
+        self.cache_timeout = cache_timeout;
+        self
+    }
+
+    /// Sets how long before their expiry a background refresh of the credentials is started.
+    ///
+    /// Between `expiry - refresh_ahead` and `expiry - cache_timeout`, reads return the cached
+    /// credentials immediately and start a refresh in the background, so that callers don't have
+    /// to wait for STS. The background refresh is started with the [`spawner`](Self::spawner).
+    ///
+    /// Must be longer than `cache_timeout`. Disabled by default.
+    pub fn refresh_ahead(mut self, refresh_ahead: Duration) -> Self {
+        self.refresh_ahead = Some(refresh_ahead);
+        self
+    }
+
+    /// Sets how much time may pass between handing out the credentials and their use.
+    ///
+    /// Freshness is computed as if the credentials expired `delivery_cushion` earlier than they
+    /// actually do, which accounts for systems that buffer credentials or queue work before making
+    /// requests with them. Unlike `cache_timeout`, which is the margin kept before refreshing, this
+    /// is the expected delay on the caller's side, and it applies to `refresh_ahead` as well.
+    ///
+    /// Defaults to zero.
+    pub fn delivery_cushion(mut self, delivery_cushion: Duration) -> Self {
+        self.delivery_cushion = delivery_cushion;
+        self
+    }
+
+    /// Sets the HTTP connector used to reach STS.
+    ///
+    /// This is useful when STS must be reached through a proxy: wrap a proxying `hyper` connector
+    /// with `aws_smithy_client::hyper_ext::Adapter` and pass it here. When unset, the default HTTPS
+    /// connector of the SDK is used.
+    ///
+    /// Only the calls to STS go through this connector. The source credentials are still resolved
+    /// from the environment by `aws_config`, which may make its own HTTP calls (e.g. to IMDS).
+    pub fn connector(mut self, connector: DynConnector) -> Self {
+        self.connector = Some(connector);
+        self
+    }
+
+    /// Sets the resolver of the STS endpoint.
+    ///
+    /// The resolver is given the region STS is called in. This allows reaching STS through an
+    /// internal proxy, or selecting the endpoint dynamically. A fixed endpoint can be set by
+    /// passing an `aws_sdk_sts::Endpoint`. The resolver takes precedence over the
+    /// `sts-global-endpoint` feature, which only changes the region given to the default resolver.
+    pub fn endpoint_resolver(mut self, resolver: impl ResolveAwsEndpoint + 'static) -> Self {
+        self.endpoint_resolver = Some(EndpointResolver(Arc::new(resolver)));
+        self
+    }
+
+    /// Sources the credentials used to call STS from the named profile of the AWS config files.
+    ///
+    /// The region is still read from `AWS_REGION` first, then from the profile. By default, both
+    /// are resolved from the environment as with `aws_config::load_from_env`.
+    pub fn source_profile(mut self, profile_name: impl Into<String>) -> Self {
+        self.source = Source::Profile(profile_name.into());
+        self.set_source_setter("source_profile");
+        self
+    }
+
+    /// Sources the credentials used to call STS from the instance metadata service.
+    ///
+    /// The region is read from `AWS_REGION` first, then from IMDS. This skips the other providers
+    /// of the default chain, and failures to reach IMDS are reported with a hint about the usual
+    /// causes, such as an IMDSv2 hop limit too low for containers.
+    pub fn imds_source(mut self) -> Self {
+        self.source = Source::Imds;
+        self.set_source_setter("imds_source");
+        self
+    }
+
+    /// Adds a role to fall back to when the previous ones can't be assumed.
+    ///
+    /// Fallback roles are tried in the order they are added. They are sent the same session tags
+    /// as the primary role.
+    pub fn fallback_role(mut self, role: FallbackRole) -> Self {
+        self.fallbacks.push(role);
+        self
+    }
+
+    /// Sets what to do when STS returns credentials with a lifetime shorter than `cache_timeout`.
+    ///
+    /// Defaults to [`ShortLifetimePolicy::Serve`].
+    pub fn short_lifetime_policy(mut self, policy: ShortLifetimePolicy) -> Self {
+        self.short_lifetime = policy;
+        self
+    }
+
+    /// Sets the implementation used to sleep, for retries and timeouts.
+    ///
+    /// It is used by [`STSCredentialsProvider::wait_for_credentials`], by the STS client and by
+    /// `aws_config` when resolving the source credentials. This allows using these on runtimes
+    /// other than Tokio, together with [`spawner`](Self::spawner).
+    ///
+    /// Defaults to the Tokio sleep with the `rt-tokio` feature, and must be set otherwise.
+    pub fn sleep_impl(mut self, sleep: Arc<dyn AsyncSleep>) -> Self {
+        self.sleep = Some(sleep);
+        self
+    }
+
+    /// Sets what runs the background refreshes started by [`refresh_ahead`](Self::refresh_ahead).
+    ///
+    /// Defaults to [`TokioSpawn`](crate::TokioSpawn) with the `rt-tokio` feature. Without it,
+    /// [`build`](Self::build) fails if `refresh_ahead` is set but no spawner is.
+    pub fn spawner(mut self, spawner: Arc<dyn Spawn>) -> Self {
+        self.spawner = Some(spawner);
+        self
+    }
+
+    /// Sets the clock used to decide whether credentials are stale.
+    ///
+    /// This is meant for tests and environments where the time is warped, e.g. with a
+    /// [`ManualTimeSource`](aws_types::os_shim_internal::ManualTimeSource). The same time source
+    /// is given to the providers of the source credentials built for
+    /// [`source_profile`](Self::source_profile) and [`imds_source`](Self::imds_source), so that
+    /// both caches agree on expiries. They must agree: credentials the source considers fresh
+    /// could otherwise be expired for STS, or the other way round.
+    ///
+    /// The time source can't be given to the default chain of `aws_config`, nor to the STS client
+    /// of this SDK version, which signs requests with the system clock. Waits, such as backoffs and
+    /// the lock timeout, go through [`sleep_impl`](Self::sleep_impl) instead. Defaults to the
+    /// system clock.
+    pub fn time_source(mut self, time: TimeSource) -> Self {
+        self.time = time;
+        self
+    }
+
+    /// Sets what to do when credentials obtained from STS were issued in the future of the local
+    /// clock.
+    ///
+    /// The local clock is the one set with [`time_source`](Self::time_source). Defaults to
+    /// [`ClockSkewPolicy::Wait`].
+    pub fn clock_skew_policy(mut self, clock_skew: ClockSkewPolicy) -> Self {
+        self.clock_skew = clock_skew;
+        self
+    }
+
+    /// Assumes the role with `AssumeRoleWithWebIdentity` and tokens from `tokens`, instead of
+    /// `AssumeRole` and source credentials.
+    ///
+    /// Any federated flow can drive the provider this way, by implementing
+    /// [`IdentityTokenProvider`]; [`FileTokenProvider`](crate::FileTokenProvider) and
+    /// [`EnvTokenProvider`](crate::EnvTokenProvider) read the token from a file or an environment
+    /// variable. The call isn't signed, so the options choosing the source credentials can't be
+    /// combined with this one, nor can [`passthrough`](Self::passthrough).
+    ///
+    /// `AssumeRoleWithWebIdentity` takes neither an external id, a source identity nor session
+    /// tags, which come from the token, so these can't be set either. Fallback roles are assumed
+    /// with the same tokens, ignoring their external id and source identity.
+    pub fn web_identity(mut self, tokens: impl IdentityTokenProvider + 'static) -> Self {
+        self.identity_tokens = Some(Arc::new(tokens));
+        self.source = Source::WebIdentity;
+        self.set_source_setter("web_identity");
+        self
+    }
+
+    /// Sets whether the cached credentials are dropped when refreshing them fails.
+    ///
+    /// When disabled, the stale credentials are kept, so that e.g.
+    /// [`cached_expiry`](STSCredentialsProvider::cached_expiry) still reports them. They are never
+    /// returned by the provider though: each read keeps trying to refresh them, and returns the
+    /// error until it succeeds. Background refreshes (see [`refresh_ahead`](Self::refresh_ahead))
+    /// never clear the cache, as the cached credentials are still valid then.
+    ///
+    /// Enabled by default.
+    pub fn clear_cache_on_error(mut self, clear_cache_on_error: bool) -> Self {
+        self.clear_cache_on_error = clear_cache_on_error;
+        self
+    }
+
+    /// Sets how long a read may wait for a refresh made by another task.
+    ///
+    /// Refreshes are single-flight: when the credentials are stale, the first read refreshes them
+    /// while holding the lock of the cache, and the reads that come meanwhile wait for it to
+    /// complete, then return the new credentials. With a timeout, waiting reads give up after
+    /// `lock_timeout`, returning the stale credentials if they haven't expired yet, or an error
+    /// otherwise. The refresh itself isn't interrupted.
+    ///
+    /// Unlimited by default.
+    pub fn lock_timeout(mut self, lock_timeout: Duration) -> Self {
+        self.lock_timeout = Some(lock_timeout);
+        self
+    }
+
+    /// Sets what reads finding stale credentials do while another task is refreshing them.
+    ///
+    /// See [`InFlightPolicy`] for the trade-offs. [`InFlightPolicy::FailFast`] can't be combined
+    /// with [`lock_timeout`](Self::lock_timeout), as it never waits. Defaults to
+    /// [`InFlightPolicy::Wait`].
+    pub fn in_flight_policy(mut self, in_flight: InFlightPolicy) -> Self {
+        self.in_flight = in_flight;
+        self
+    }
+
+    /// Caps the number of `AssumeRole` calls the provider makes over its lifetime.
+    ///
+    /// Once `max_calls` calls have been made, successful or not, the provider stops calling STS.
+    /// Reads then return the stale credentials if they haven't expired yet, or an error otherwise.
+    /// This is a safety valve against runaway refresh loops, and bounds the calls made by tests.
+    ///
+    /// It is a blunt instrument: nothing resets the count, so a long-running process eventually
+    /// ends up without credentials. Every call counts, including retries and calls for fallback
+    /// roles, and none is sent past the cap, even within a load started before it. The count is
+    /// shared with the providers derived through
+    /// [`with_overrides`](STSCredentialsProvider::with_overrides) and
+    /// [`for_environment`](STSCredentialsProvider::for_environment).
+    ///
+    /// Unlimited by default.
+    pub fn max_lifetime_calls(mut self, max_calls: u64) -> Self {
+        self.max_lifetime_calls = Some(max_calls);
+        self
+    }
+
+    /// Sets the provider name carried by the credentials.
+    ///
+    /// The SDK shows it in its diagnostics, which helps telling providers apart when several are
+    /// used. Must not be empty. Defaults to `STSCredentialsProvider`.
+    pub fn provider_name(mut self, provider_name: &'static str) -> Self {
+        self.provider_name = provider_name;
+        self
+    }
+
+    /// Sets the application name sent by the STS client in its user agent.
+    ///
+    /// It shows up in CloudTrail and lets STS usage be attributed to an application. It takes
+    /// precedence over the one of the SDK configuration, and must only contain the characters the
+    /// SDK accepts, which [`build`](Self::build) checks.
+    pub fn app_name(mut self, app_name: impl Into<String>) -> Self {
+        self.app_name = Some(app_name.into());
+        self
+    }
+
+    /// Sets whether the source credentials are returned as-is, without assuming the role.
+    ///
+    /// This is meant for local development, so that the same code runs with and without role
+    /// assumption, e.g. with personal credentials that already have the needed permissions. The
+    /// provider, as well as [`wait_for_credentials`](STSCredentialsProvider::wait_for_credentials)
+    /// and
+    /// [`get_credentials_with_deadline`](STSCredentialsProvider::get_credentials_with_deadline),
+    /// then return whatever the source resolves to, and never call STS. The methods describing or
+    /// replacing assumed credentials, such as
+    /// [`get_credentials_with_metadata`](STSCredentialsProvider::get_credentials_with_metadata),
+    /// still assume the role.
+    ///
+    /// **Security:** none of the restrictions of the role apply in this mode. The permissions are
+    /// those of the source credentials, which may be much broader, and the external id, session
+    /// tags and every other `AssumeRole` parameter are ignored. Don't enable it in production.
+    ///
+    /// Disabled by default.
+    pub fn passthrough(mut self, passthrough: bool) -> Self {
+        self.passthrough = passthrough;
+        self
+    }
+
+    /// Sets how long a provider returned by
+    /// [`get_scoped`](STSCredentialsProvider::get_scoped) may go unused before it is evicted.
+    ///
+    /// Defaults to 15 minutes.
+    pub fn scoped_idle_ttl(mut self, idle_ttl: Duration) -> Self {
+        self.scoped_idle_ttl = idle_ttl;
+        self
+    }
+
+    /// Sets how many providers returned by [`get_scoped`](STSCredentialsProvider::get_scoped)
+    /// are kept.
+    ///
+    /// Creating an entry past this number evicts the least recently used one. Raised to 1 if 0.
+    /// Defaults to 1000.
+    pub fn scoped_max_entries(mut self, max_entries: usize) -> Self {
+        self.scoped_max_entries = max_entries.max(1);
+        self
+    }
+
+    /// Sets a limiter of the rate of `AssumeRole` calls, which may be shared with other providers.
+    ///
+    /// Each call, including retries and calls for fallback roles, waits for the limiter first.
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Sets how the session token returned by STS is handled.
+    ///
+    /// Defaults to [`SessionTokenPolicy::Passthrough`].
+    pub fn session_token_policy(mut self, policy: SessionTokenPolicy) -> Self {
+        self.session_token = policy;
+        self
+    }
+
+    /// Sets a hook called on the credentials obtained from STS, before they are cached.
+    ///
+    /// The hook receives the credentials along with the full output of the `AssumeRole` call. It
+    /// may return different credentials, e.g. to change the provider name, or an error to reject
+    /// them, e.g. when the assumed-role ARN isn't in the expected account. Rejected credentials
+    /// are not cached, and the error is returned to the caller. The returned credentials must
+    /// have an expiry.
+    pub fn post_load(
+        mut self,
+        hook: impl Fn(Credentials, &AssumeRoleOutput) -> Result<Credentials, CredentialsError>
+            + Send
+            + Sync
+            + 'static,
+    ) -> Self {
+        self.post_load = Some(PostLoad(Arc::new(hook)));
+        self
+    }
+
+    fn set_source_setter(&mut self, setter: &'static str) {
+        if !self.source_setters.contains(&setter) {
+            self.source_setters.push(setter);
+        }
+    }
+
+    /// Returns the pairs of options that were both set but can't be used together.
+    fn conflicts(&self) -> Vec<(&'static str, &'static str)> {
+        let mut conflicts = Vec::new();
+        for (i, first) in self.source_setters.iter().enumerate() {
+            for second in &self.source_setters[i + 1..] {
+                conflicts.push((*first, *second));
+            }
+        }
+        if self.selected_environment.is_some() {
+            if self.params.external_id.is_some() {
+                conflicts.push(("select_environment", "external_id"));
+            }
+            if self.params.source_identity.is_some() {
+                conflicts.push(("select_environment", "source_identity"));
+            }
+        }
+        if self.identity_tokens.is_some() {
+            let unsupported = [
+                ("external_id", self.params.external_id.is_some()),
+                ("source_identity", self.params.source_identity.is_some()),
+                ("tag", !self.params.tags.is_empty()),
+                ("select_environment", self.selected_environment.is_some()),
+                ("passthrough", self.passthrough),
+            ];
+            for (option, set) in unsupported {
+                if set {
+                    conflicts.push(("web_identity", option));
+                }
+            }
+        }
+        if self.in_flight == InFlightPolicy::FailFast && self.lock_timeout.is_some() {
+            conflicts.push(("in_flight_policy", "lock_timeout"));
+        }
+        conflicts
+    }
+
+    /// Builds the provider, checking that the options are consistent.
+    pub fn build(mut self) -> Result<STSCredentialsProvider, BuildError> {
+        let conflicts = self.conflicts();
+        if !conflicts.is_empty() {
+            return Err(BuildError::ConflictingOptions(conflicts));
+        }
+        if let Some(refresh_ahead) = self.refresh_ahead {
+            if refresh_ahead <= self.cache_timeout {
+                return Err(BuildError::InvalidRefreshAhead {
+                    refresh_ahead,
+                    cache_timeout: self.cache_timeout,
+                });
+            }
+            if self.spawner.is_none() {
+                return Err(BuildError::MissingSpawner);
+            }
+        }
+
+        if let Some(environment) = self.selected_environment {
+            let ids = self
+                .environments
+                .get(&environment)
+                .ok_or(BuildError::UnknownEnvironment(environment))?;
+            self.params.external_id = ids.external_id.clone();
+            self.params.source_identity = ids.source_identity.clone();
+        }
+        if self.provider_name.is_empty() {
+            return Err(BuildError::EmptyProviderName);
+        }
+        let app_name = self
+            .app_name
+            .map(|name| AppName::new(name.clone()).map_err(|_| BuildError::InvalidAppName(name)))
+            .transpose()?;
+        check_tags(&self.params.tags)?;
+        self.params.tags.sort();
+        let fallbacks = self
+            .fallbacks
+            .into_iter()
+            .map(|role| AssumeRoleParams {
+                tags: self.params.tags.clone(),
+                ..role.params
+            })
+            .collect();
+        #[cfg(feature = "rt-tokio")]
+        let sleep = self.sleep.unwrap_or_else(|| Arc::new(TokioSleep::new()));
+        #[cfg(not(feature = "rt-tokio"))]
+        let sleep = self.sleep.ok_or(BuildError::MissingSleepImpl)?;
+
+        Ok(STSCredentialsProvider {
+            inner: Arc::new(Inner {
+                params: self.params,
+                cred_cache: RwLock::new(None),
+                current: ArcSwapOption::empty(),
+                cache_timeout: self.cache_timeout,
+                refresh_ahead: self.refresh_ahead.unwrap_or(self.cache_timeout),
+                delivery_cushion: self.delivery_cushion,
+                refreshing: AtomicBool::new(false),
+                client: Arc::new(StsClient::new(
+                    self.source,
+                    self.connector,
+                    sleep.clone(),
+                    self.time.clone(),
+                    self.endpoint_resolver,
+                    app_name,
+                )),
+                stale_loads: AtomicU64::new(0),
+                fallbacks,
+                preferred_role: AtomicUsize::new(0),
+                short_lifetime: self.short_lifetime,
+                clock_skew: self.clock_skew,
+                short_lifetime_logged: AtomicBool::new(false),
+                sleep,
+                spawner: self.spawner,
+                time: self.time,
+                clear_cache_on_error: self.clear_cache_on_error,
+                unique_session_names: self.unique_session_names,
+                session_counter: AtomicU64::new(0),
+                load_latencies: Mutex::new(VecDeque::with_capacity(LATENCY_SAMPLES)),
+                environments: self.environments,
+                short_grant_logged: AtomicBool::new(false),
+                post_load: self.post_load,
+                session_token: self.session_token,
+                lock_timeout: self.lock_timeout,
+                in_flight: self.in_flight,
+                max_lifetime_calls: self.max_lifetime_calls,
+                sts_calls: Arc::new(AtomicU64::new(0)),
+                rate_limiter: self.rate_limiter,
+                identity_tokens: self.identity_tokens,
+                scoped: Mutex::default(),
+                scoped_idle_ttl: self.scoped_idle_ttl,
+                scoped_max_entries: self.scoped_max_entries,
+                provider_name: self.provider_name,
+                passthrough: self.passthrough,
+            }),
+        })
+    }
+
+    /// Builds the provider and assumes the role right away.
+    ///
+    /// Unlike [`build`](Self::build), which defers the `AssumeRole` call to the first use of the
+    /// provider, this either returns a provider with a populated cache or the error that prevented
+    /// assuming the role. This lets processes that must not start without credentials fail fast.
+    ///
+    /// With the default connector, this must be awaited from within a Tokio runtime.
+    pub async fn build_and_assume(self) -> Result<STSCredentialsProvider, CredentialsError> {
+        let provider = self
+            .build()
+            .map_err(CredentialsError::invalid_configuration)?;
+        provider.get_credentials().await?;
+        Ok(provider)
+    }
+}
+
+/// A role to fall back to when the primary role can't be assumed.
+///
+/// See [`STSCredentialsProviderBuilder::fallback_role`].
+#[derive(Debug)]
+pub struct FallbackRole {
+    params: AssumeRoleParams,
+}
+
+impl FallbackRole {
+    pub fn new(role_arn: &str) -> Self {
+        Self {
+            params: AssumeRoleParams {
+                role_arn: role_arn.to_string(),
+                external_id: None,
+                source_identity: None,
+                session_name: None,
+                session_duration: None,
+                tags: Vec::new(),
+                policy: None,
+            },
+        }
+    }
+
+    /// Sets the external id sent when assuming this role.
+    pub fn external_id(mut self, external_id: impl Into<String>) -> Self {
+        self.params.external_id = Some(external_id.into());
+        self
+    }
+
+    /// Sets the source identity sent when assuming this role.
+    pub fn source_identity(mut self, source_identity: impl Into<String>) -> Self {
+        self.params.source_identity = Some(source_identity.into());
+        self
+    }
+
+    /// Sets the role session name sent when assuming this role.
+    pub fn session_name(mut self, session_name: impl Into<String>) -> Self {
+        self.params.session_name = Some(session_name.into());
+        self
+    }
+
+    /// Sets the requested session duration for this role, in seconds.
+    pub fn session_duration(mut self, session_duration: i32) -> Self {
+        self.params.session_duration = Some(session_duration);
+        self
+    }
+}
+
+/// Provider name carried by the credentials unless set on the builder
+const DEFAULT_PROVIDER_NAME: &str = "STSCredentialsProvider";
+
+/// How long a scoped provider may go unused unless set on the builder
+const DEFAULT_SCOPED_IDLE_TTL: Duration = Duration::from_secs(15 * 60);
+
+/// How many scoped providers are kept unless set on the builder
+const DEFAULT_SCOPED_MAX_ENTRIES: usize = 1000;
+
+/// Environment variable holding the ARN of the role to assume
+const ENV_ROLE_ARN: &str = "STS_ROLE_ARN";
+/// Environment variable holding the external id
+const ENV_EXTERNAL_ID: &str = "STS_EXTERNAL_ID";
+/// Environment variable holding the role session name
+const ENV_SESSION_NAME: &str = "STS_SESSION_NAME";
+/// Environment variable holding the session duration, in seconds
+const ENV_SESSION_DURATION: &str = "STS_SESSION_DURATION";
+/// Environment variable holding the cache timeout, in seconds
+const ENV_CACHE_TIMEOUT: &str = "STS_CACHE_TIMEOUT";
+
+/// Reads an environment variable, treating an empty value as unset.
+fn env_var(name: &'static str) -> Result<Option<String>, BuildError> {
+    match env::var(name) {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(value)) => Err(BuildError::InvalidEnvVar {
+            name,
+            // The external id is a shared secret
+            value: match name {
+                ENV_EXTERNAL_ID => REDACTED.to_string(),
+                _ => value.to_string_lossy().into_owned(),
+            },
+        }),
+    }
+}
+
+/// Reads and parses an environment variable, treating an empty value as unset.
+fn parsed_env_var<T: std::str::FromStr>(name: &'static str) -> Result<Option<T>, BuildError> {
+    env_var(name)?
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|_| BuildError::InvalidEnvVar { name, value })
+        })
+        .transpose()
+}
+
+/// Maximum number of session tags accepted by STS
+const MAX_TAGS: usize = 50;
+/// Maximum length of a session tag key accepted by STS
+const MAX_TAG_KEY_LENGTH: usize = 128;
+/// Maximum length of a session tag value accepted by STS
+const MAX_TAG_VALUE_LENGTH: usize = 256;
+
+/// Checks the session tags against the limits of STS.
+fn check_tags(tags: &[(String, String)]) -> Result<(), BuildError> {
+    if tags.len() > MAX_TAGS {
+        return Err(BuildError::TooManyTags(tags.len()));
+    }
+    let mut keys = HashSet::new();
+    for (key, value) in tags {
+        if key.is_empty() || key.chars().count() > MAX_TAG_KEY_LENGTH {
+            return Err(BuildError::InvalidTagKey(key.clone()));
+        }
+        if value.chars().count() > MAX_TAG_VALUE_LENGTH {
+            return Err(BuildError::InvalidTagValue(key.clone()));
+        }
+        if !keys.insert(key) {
+            return Err(BuildError::DuplicateTagKey(key.clone()));
+        }
+    }
+    Ok(())
+}
+
+/// Error returned when the options given to the builder are inconsistent.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// `refresh_ahead` isn't longer than `cache_timeout`.
+    InvalidRefreshAhead {
+        refresh_ahead: Duration,
+        cache_timeout: Duration,
+    },
+    /// More session tags than STS accepts were set.
+    TooManyTags(usize),
+    /// A session tag key is empty or too long.
+    InvalidTagKey(String),
+    /// The value of the session tag with the given key is too long.
+    InvalidTagValue(String),
+    /// A session tag key was set more than once.
+    DuplicateTagKey(String),
+    /// A required environment variable isn't set.
+    MissingEnvVar(&'static str),
+    /// An environment variable has a value that can't be used.
+    InvalidEnvVar { name: &'static str, value: String },
+    /// The selected environment wasn't declared.
+    UnknownEnvironment(String),
+    /// The provider name is empty.
+    EmptyProviderName,
+    /// The application name contains characters the SDK doesn't accept.
+    InvalidAppName(String),
+    /// No sleep implementation was set, while the `rt-tokio` feature is disabled.
+    MissingSleepImpl,
+    /// `refresh_ahead` was set without a spawner for the background refreshes.
+    MissingSpawner,
+    /// Options that can't be used together were set, listed by pairs.
+    ///
+    /// The source of the credentials used to call STS can only be set once, and selecting an
+    /// environment excludes setting the external id or the source identity directly. A lock
+    /// timeout can't be set when reads don't wait for refreshes. Web identities exclude the options
+    /// `AssumeRoleWithWebIdentity` doesn't support.
+    ConflictingOptions(Vec<(&'static str, &'static str)>),
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::InvalidRefreshAhead {
+                refresh_ahead,
+                cache_timeout,
+            } => write!(
+                f,
+                "refresh_ahead ({}s) must be longer than cache_timeout ({}s)",
+                refresh_ahead.as_secs(),
+                cache_timeout.as_secs()
+            ),
+            BuildError::TooManyTags(count) => write!(
+                f,
+                "{} session tags were set, STS accepts at most {}",
+                count, MAX_TAGS
+            ),
+            BuildError::InvalidTagKey(key) => write!(
+                f,
+                "session tag key \"{}\" must be between 1 and {} characters long",
+                key, MAX_TAG_KEY_LENGTH
+            ),
+            BuildError::InvalidTagValue(key) => write!(
+                f,
+                "the value of session tag \"{}\" must be at most {} characters long",
+                key, MAX_TAG_VALUE_LENGTH
+            ),
+            BuildError::DuplicateTagKey(key) => {
+                write!(f, "session tag key \"{}\" was set more than once", key)
+            }
+            BuildError::MissingEnvVar(name) => {
+                write!(f, "environment variable {} must be set", name)
+            }
+            BuildError::InvalidEnvVar { name, value } => {
+                write!(
+                    f,
+                    "invalid value \"{}\" for environment variable {}",
+                    value, name
+                )
+            }
+            BuildError::UnknownEnvironment(environment) => {
+                write!(
+                    f,
+                    "environment \"{}\" was selected but not declared",
+                    environment
+                )
+            }
+            BuildError::EmptyProviderName => write!(f, "the provider name must not be empty"),
+            BuildError::InvalidAppName(app_name) => {
+                write!(f, "invalid application name \"{}\"", app_name)
+            }
+            BuildError::MissingSleepImpl => {
+                write!(
+                    f,
+                    "a sleep implementation must be set without the rt-tokio feature"
+                )
+            }
+            BuildError::MissingSpawner => {
+                write!(
+                    f,
+                    "refresh_ahead requires a spawner for the background refreshes"
+                )
+            }
+            BuildError::ConflictingOptions(conflicts) => {
+                let conflicts: Vec<_> = conflicts
+                    .iter()
+                    .map(|(first, second)| format!("{} and {}", first, second))
+                    .collect();
+                write!(
+                    f,
+                    "options that can't be used together were set: {}",
+                    conflicts.join(", ")
+                )
+            }
+        }
+    }
+}
+
+impl Error for BuildError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::EnvTokenProvider;
+
+    /// Serializes the tests reading the variables of [`STSCredentialsProviderBuilder::from_env`].
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    /// Runs `f` with the given variables set, and the other variables of `from_env` unset.
+    fn with_env(vars: &[(&'static str, &str)], f: impl FnOnce()) {
+        let _lock = ENV_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let names = [
+            ENV_ROLE_ARN,
+            ENV_EXTERNAL_ID,
+            ENV_SESSION_NAME,
+            ENV_SESSION_DURATION,
+            ENV_CACHE_TIMEOUT,
+        ];
+        for name in names {
+            env::remove_var(name);
+        }
+        for (name, value) in vars {
+            env::set_var(name, value);
+        }
+        f();
+        for name in names {
+            env::remove_var(name);
+        }
+    }
+
+    #[test]
+    fn from_env_reads_every_variable() {
+        let vars = [
+            (ENV_ROLE_ARN, "arn:aws:iam::123456789012:role/example"),
+            (ENV_EXTERNAL_ID, "external"),
+            (ENV_SESSION_NAME, "session"),
+            (ENV_SESSION_DURATION, "900"),
+            (ENV_CACHE_TIMEOUT, " 120 "),
+        ];
+        with_env(&vars, || {
+            let builder = STSCredentialsProviderBuilder::from_env().unwrap();
+            assert_eq!(
+                builder.params.role_arn,
+                "arn:aws:iam::123456789012:role/example"
+            );
+            assert_eq!(builder.params.external_id.as_deref(), Some("external"));
+            assert_eq!(builder.params.session_name.as_deref(), Some("session"));
+            assert_eq!(builder.params.session_duration, Some(900));
+            assert_eq!(builder.cache_timeout, Duration::from_secs(120));
+        });
+    }
+
+    #[test]
+    fn from_env_treats_empty_variables_as_unset() {
+        let vars = [
+            (ENV_ROLE_ARN, "arn:aws:iam::123456789012:role/example"),
+            (ENV_EXTERNAL_ID, ""),
+            (ENV_SESSION_DURATION, ""),
+        ];
+        with_env(&vars, || {
+            let builder = STSCredentialsProviderBuilder::from_env().unwrap();
+            assert_eq!(builder.params.external_id, None);
+            assert_eq!(builder.params.session_name, None);
+            assert_eq!(builder.params.session_duration, None);
+            assert_eq!(builder.cache_timeout, Duration::from_secs(60));
+        });
+    }
+
+    #[test]
+    fn from_env_requires_the_role_arn() {
+        for vars in [&[][..], &[(ENV_ROLE_ARN, "")][..]] {
+            with_env(vars, || {
+                assert!(matches!(
+                    STSCredentialsProviderBuilder::from_env(),
+                    Err(BuildError::MissingEnvVar(ENV_ROLE_ARN))
+                ));
+            });
+        }
+    }
+
+    #[test]
+    fn from_env_rejects_values_that_dont_parse() {
+        let vars = [
+            (ENV_ROLE_ARN, "arn:aws:iam::123456789012:role/example"),
+            (ENV_SESSION_DURATION, "an hour"),
+        ];
+        with_env(&vars, || match STSCredentialsProviderBuilder::from_env() {
+            Err(BuildError::InvalidEnvVar { name, value }) => {
+                assert_eq!(name, ENV_SESSION_DURATION);
+                assert_eq!(value, "an hour");
+            }
+            result => panic!("unexpected result: {:?}", result),
+        });
+    }
+
+    fn tags(tags: &[(&str, &str)]) -> Vec<(String, String)> {
+        tags.iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn string_options_accept_owned_and_optional_values() {
+        let external_id: String = format!("tenant-{}", 42);
+        let builder = STSCredentialsProvider::builder("arn:aws:iam::123456789012:role/example")
+            .external_id(external_id)
+            .set_source_identity(Some("alice".to_string()))
+            .session_name("session")
+            .set_session_name(None);
+        assert_eq!(builder.params.external_id.as_deref(), Some("tenant-42"));
+        assert_eq!(builder.params.source_identity.as_deref(), Some("alice"));
+        assert_eq!(builder.params.session_name, None);
+    }
+
+    #[test]
+    fn tags_within_the_limits_of_sts_are_accepted() {
+        check_tags(&[]).unwrap();
+        let key = "k".repeat(MAX_TAG_KEY_LENGTH);
+        let value = "v".repeat(MAX_TAG_VALUE_LENGTH);
+        check_tags(&tags(&[(&key, &value), ("empty", "")])).unwrap();
+        let many: Vec<_> = (0..MAX_TAGS)
+            .map(|i| (i.to_string(), String::new()))
+            .collect();
+        check_tags(&many).unwrap();
+    }
+
+    #[test]
+    fn tags_beyond_the_limits_of_sts_are_rejected() {
+        let many: Vec<_> = (0..=MAX_TAGS)
+            .map(|i| (i.to_string(), String::new()))
+            .collect();
+        assert!(matches!(
+            check_tags(&many),
+            Err(BuildError::TooManyTags(count)) if count == MAX_TAGS + 1
+        ));
+        let key = "k".repeat(MAX_TAG_KEY_LENGTH + 1);
+        assert!(matches!(
+            check_tags(&tags(&[(&key, "value")])),
+            Err(BuildError::InvalidTagKey(_))
+        ));
+        assert!(matches!(
+            check_tags(&tags(&[("", "value")])),
+            Err(BuildError::InvalidTagKey(_))
+        ));
+        let value = "v".repeat(MAX_TAG_VALUE_LENGTH + 1);
+        assert!(matches!(
+            check_tags(&tags(&[("key", &value)])),
+            Err(BuildError::InvalidTagValue(key)) if key == "key"
+        ));
+    }
+
+    #[test]
+    fn duplicate_tags_are_rejected() {
+        let result = STSCredentialsProvider::builder("arn:aws:iam::123456789012:role/example")
+            .tag("team", "a")
+            .tag("team", "b")
+            .build();
+        assert!(matches!(result, Err(BuildError::DuplicateTagKey(key)) if key == "team"));
+    }
+
+    #[test]
+    fn tags_are_sorted_by_key() {
+        let provider = STSCredentialsProvider::builder("arn:aws:iam::123456789012:role/example")
+            .tags(HashMap::from([
+                ("b".to_string(), "2".to_string()),
+                ("c".to_string(), "3".to_string()),
+                ("a".to_string(), "1".to_string()),
+            ]))
+            .build()
+            .unwrap();
+        assert_eq!(
+            provider.inner.params.tags,
+            tags(&[("a", "1"), ("b", "2"), ("c", "3")])
+        );
+    }
+
+    /// Returns the conflicts reported when building with the given options.
+    fn conflicts_of(
+        configure: impl FnOnce(STSCredentialsProviderBuilder) -> STSCredentialsProviderBuilder,
+    ) -> Vec<(&'static str, &'static str)> {
+        let builder = STSCredentialsProvider::builder("arn:aws:iam::123456789012:role/example");
+        match configure(builder).build() {
+            Err(BuildError::ConflictingOptions(conflicts)) => conflicts,
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn a_single_source_of_credentials_can_be_set() {
+        assert_eq!(
+            conflicts_of(|b| b.source_profile("dev").imds_source().source_profile("prod")),
+            [("source_profile", "imds_source")]
+        );
+        assert!(conflicts_of(|b| b.source_profile("dev")).is_empty());
+    }
+
+    #[test]
+    fn selected_environments_replace_the_ids() {
+        assert_eq!(
+            conflicts_of(|b| b
+                .environment("staging", EnvironmentIds::default())
+                .select_environment("staging")
+                .external_id("external")
+                .source_identity("identity")),
+            [
+                ("select_environment", "external_id"),
+                ("select_environment", "source_identity")
+            ]
+        );
+    }
+
+    #[test]
+    fn web_identities_dont_support_the_options_of_assume_role() {
+        assert_eq!(
+            conflicts_of(|b| b
+                .web_identity(EnvTokenProvider::new("TOKEN"))
+                .external_id("external")
+                .tag("team", "a")
+                .passthrough(true)),
+            [
+                ("web_identity", "external_id"),
+                ("web_identity", "tag"),
+                ("web_identity", "passthrough")
+            ]
+        );
+        assert!(conflicts_of(|b| b.web_identity(EnvTokenProvider::new("TOKEN"))).is_empty());
+    }
+
+    #[test]
+    fn failing_fast_doesnt_wait_for_the_lock() {
+        assert_eq!(
+            conflicts_of(|b| b
+                .in_flight_policy(InFlightPolicy::FailFast)
+                .lock_timeout(Duration::from_secs(1))),
+            [("in_flight_policy", "lock_timeout")]
+        );
+        assert!(conflicts_of(|b| b.lock_timeout(Duration::from_secs(1))).is_empty());
+    }
+
+    #[test]
+    fn refreshing_ahead_requires_a_spawner() {
+        let mut builder = STSCredentialsProvider::builder("arn:aws:iam::123456789012:role/example")
+            .refresh_ahead(Duration::from_secs(300));
+        // As without the rt-tokio feature
+        builder.spawner = None;
+        assert!(matches!(builder.build(), Err(BuildError::MissingSpawner)));
+
+        let mut builder = STSCredentialsProvider::builder("arn:aws:iam::123456789012:role/example");
+        builder.spawner = None;
+        assert!(builder.build().is_ok());
+    }
+}
@@ -0,0 +1,401 @@
+use crate::error::ContextError;
+use aws_config::environment::region::EnvironmentVariableRegionProvider;
+use aws_config::imds::credentials::ImdsCredentialsProvider;
+use aws_config::imds::region::ImdsRegionProvider;
+use aws_config::meta::credentials::LazyCachingCredentialsProvider;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::profile::{ProfileFileCredentialsProvider, ProfileFileRegionProvider};
+use aws_config::provider_config::ProviderConfig;
+use aws_endpoint::{AwsEndpoint, BoxError, ResolveAwsEndpoint};
+use aws_sdk_sts::{AppName, Client};
+use aws_smithy_async::rt::sleep::AsyncSleep;
+use aws_smithy_client::erase::DynConnector;
+use aws_types::config::Config;
+use aws_types::credentials::{self, CredentialsError, SharedCredentialsProvider};
+use aws_types::os_shim_internal::TimeSource;
+use aws_types::region::Region;
+use http::Uri;
+use std::fmt::{self, Debug, Formatter};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+/// Pseudo-region selecting the legacy global STS endpoint, `sts.amazonaws.com`
+#[cfg(feature = "sts-global-endpoint")]
+const GLOBAL_ENDPOINT_REGION: &str = "aws-global";
+
+/// Where the credentials used to call STS come from.
+#[derive(Debug)]
+pub(crate) enum Source {
+    /// The default chain of `aws_config`
+    Environment,
+    /// A named profile of the AWS config files
+    Profile(String),
+    /// The instance metadata service
+    Imds,
+    /// No source credentials, the role is assumed with a web identity token
+    WebIdentity,
+}
+
+/// Resolver of the STS endpoint set on the builder.
+#[derive(Clone)]
+pub(crate) struct EndpointResolver(pub(crate) Arc<dyn ResolveAwsEndpoint>);
+
+impl ResolveAwsEndpoint for EndpointResolver {
+    fn resolve_endpoint(&self, region: &Region) -> Result<AwsEndpoint, BoxError> {
+        self.0.resolve_endpoint(region)
+    }
+}
+
+impl Debug for EndpointResolver {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("EndpointResolver")
+    }
+}
+
+/// Lazily builds the STS client and keeps it for subsequent calls.
+///
+/// It may be shared by several providers.
+#[derive(Debug)]
+pub(crate) struct StsClient {
+    source: Source,
+    connector: Option<DynConnector>,
+    sleep: Arc<dyn AsyncSleep>,
+    time: TimeSource,
+    endpoint_resolver: Option<EndpointResolver>,
+    app_name: Option<AppName>,
+    /// The client, along with the region it calls STS in
+    client: RwLock<Option<(Client, Option<Region>)>>,
+    /// The provider of the source credentials, for the passthrough mode
+    source_provider: RwLock<Option<SharedCredentialsProvider>>,
+}
+
+impl StsClient {
+    pub(crate) fn new(
+        source: Source,
+        connector: Option<DynConnector>,
+        sleep: Arc<dyn AsyncSleep>,
+        time: TimeSource,
+        endpoint_resolver: Option<EndpointResolver>,
+        app_name: Option<AppName>,
+    ) -> Self {
+        Self {
+            source,
+            connector,
+            sleep,
+            time,
+            endpoint_resolver,
+            app_name,
+            client: RwLock::new(None),
+            source_provider: RwLock::new(None),
+        }
+    }
+
+    /// Returns the STS client, building it on first use.
+    ///
+    /// If building the client fails, the next call tries again.
+    pub(crate) async fn get(&self) -> Result<Client, CredentialsError> {
+        if let Some((client, _)) = self.client.read().await.as_ref() {
+            return Ok(client.clone());
+        }
+        let mut lock = self.client.write().await;
+        // Another task may have built it while we were waiting for the lock
+        if let Some((client, _)) = lock.as_ref() {
+            return Ok(client.clone());
+        }
+        let sts_config = self.config().await;
+        check_sts_config(&sts_config, &self.source).await?;
+        let mut conf = aws_sdk_sts::config::Builder::from(&sts_config);
+        if let Some(app_name) = &self.app_name {
+            conf = conf.app_name(app_name.clone());
+        }
+        // A custom resolver takes precedence over the global endpoint
+        let region = match &self.endpoint_resolver {
+            Some(resolver) => {
+                conf = conf.endpoint_resolver(resolver.clone());
+                sts_config.region().cloned()
+            }
+            None => default_region(&sts_config),
+        };
+        let conf = conf.region(region.clone()).build();
+        let client = match &self.connector {
+            Some(connector) => Client::from_conf_conn(conf, connector.clone()),
+            None => Client::from_conf(conf),
+        };
+        *lock = Some((client.clone(), region));
+        Ok(client)
+    }
+
+    /// Returns the source credentials, without calling STS.
+    ///
+    /// The source provider is resolved on first use, and caches the credentials itself.
+    pub(crate) async fn source_credentials(&self) -> credentials::Result {
+        let provider = self.source_provider.read().await.clone();
+        let provider = match provider {
+            Some(provider) => provider,
+            None => {
+                let mut lock = self.source_provider.write().await;
+                match lock.as_ref() {
+                    Some(provider) => provider.clone(),
+                    None => {
+                        let provider = self
+                            .config()
+                            .await
+                            .credentials_provider()
+                            .cloned()
+                            .ok_or_else(|| {
+                                CredentialsError::invalid_configuration(
+                                    "no source credentials provider is configured",
+                                )
+                            })?;
+                        *lock = Some(provider.clone());
+                        provider
+                    }
+                }
+            }
+        };
+        credentials::ProvideCredentials::provide_credentials(&provider).await
+    }
+
+    /// Returns the region STS is called in, if the client has been built.
+    pub(crate) async fn region(&self) -> Option<Region> {
+        self.client
+            .read()
+            .await
+            .as_ref()
+            .and_then(|(_, region)| region.clone())
+    }
+
+    /// Describes the region and endpoint STS is called with, for error messages.
+    ///
+    /// None of it is secret. A custom endpoint is resolved again, so that the URL is shown.
+    pub(crate) async fn target(&self) -> String {
+        let region = self.region().await;
+        let endpoint = match (&self.endpoint_resolver, &region) {
+            (Some(resolver), Some(region)) => match resolver.resolve_endpoint(region) {
+                Ok(endpoint) => {
+                    let mut uri = Uri::from_static("/");
+                    endpoint.set_endpoint(&mut uri, None);
+                    format!("endpoint {}", uri)
+                }
+                Err(_) => "a custom endpoint that can't be resolved".to_string(),
+            },
+            (Some(_), None) => "a custom endpoint".to_string(),
+            (None, _) => "the default endpoint of the SDK".to_string(),
+        };
+        let region = region.as_ref().map_or("(none)", |r| r.as_ref());
+        format!("region {}, {}", region, endpoint)
+    }
+
+    /// Drops the STS client, so that the next call builds a new one.
+    ///
+    /// This also reloads the source credentials, which are cached by the client.
+    pub(crate) async fn reset(&self) {
+        *self.client.write().await = None;
+    }
+
+    /// Loads the configuration of the STS client from the configured source.
+    ///
+    /// The providers built here are given the time source of the provider, so that they agree with
+    /// it on the expiry of the source credentials. The default chain of `aws_config` can't be given
+    /// one, and uses the system clock.
+    async fn config(&self) -> Config {
+        // The other settings keep the defaults the providers would use otherwise
+        let provider_config = ProviderConfig::default().with_time_source(self.time.clone());
+        match &self.source {
+            // The default chain is still resolved, but only the region is used for web identities
+            Source::Environment | Source::WebIdentity => {
+                aws_config::from_env()
+                    .sleep_impl(self.sleep.clone())
+                    .load()
+                    .await
+            }
+            Source::Profile(profile_name) => {
+                let region =
+                    RegionProviderChain::first_try(EnvironmentVariableRegionProvider::new())
+                        .or_else(
+                            ProfileFileRegionProvider::builder()
+                                .configure(&provider_config)
+                                .profile_name(profile_name)
+                                .build(),
+                        );
+                // The profile provider doesn't cache, and it may itself call STS
+                let credentials = LazyCachingCredentialsProvider::builder()
+                    .configure(&provider_config)
+                    .load(
+                        ProfileFileCredentialsProvider::builder()
+                            .configure(&provider_config)
+                            .profile_name(profile_name)
+                            .build(),
+                    )
+                    .build();
+                aws_config::from_env()
+                    .region(region)
+                    .credentials_provider(credentials)
+                    .sleep_impl(self.sleep.clone())
+                    .load()
+                    .await
+            }
+            Source::Imds => {
+                let region =
+                    RegionProviderChain::first_try(EnvironmentVariableRegionProvider::new())
+                        .or_else(
+                            ImdsRegionProvider::builder()
+                                .configure(&provider_config)
+                                .build(),
+                        );
+                // The IMDS provider doesn't cache either
+                let credentials = LazyCachingCredentialsProvider::builder()
+                    .configure(&provider_config)
+                    .load(
+                        ImdsCredentialsProvider::builder()
+                            .configure(&provider_config)
+                            .build(),
+                    )
+                    .build();
+                aws_config::from_env()
+                    .region(region)
+                    .credentials_provider(credentials)
+                    .sleep_impl(self.sleep.clone())
+                    .load()
+                    .await
+            }
+        }
+    }
+}
+
+/// Returns the region to call STS in with the endpoint resolver of the SDK.
+#[cfg(feature = "sts-global-endpoint")]
+fn default_region(_config: &Config) -> Option<Region> {
+    // The endpoint resolver of the SDK maps this pseudo-region to the global endpoint
+    Some(Region::new(GLOBAL_ENDPOINT_REGION))
+}
+
+/// Returns the region to call STS in with the endpoint resolver of the SDK.
+#[cfg(not(feature = "sts-global-endpoint"))]
+fn default_region(config: &Config) -> Option<Region> {
+    config.region().cloned()
+}
+
+/// Checks that the configuration resolved from the environment can be used to call STS.
+///
+/// This turns the confusing errors returned by the STS call into precise ones.
+async fn check_sts_config(config: &Config, source: &Source) -> Result<(), CredentialsError> {
+    // The global endpoint doesn't need a region
+    if config.region().is_none() && cfg!(not(feature = "sts-global-endpoint")) {
+        return Err(CredentialsError::invalid_configuration(
+            "no region is configured for STS, set one through AWS_REGION or the AWS profile",
+        ));
+    }
+    // AssumeRoleWithWebIdentity calls aren't signed
+    if let Source::WebIdentity = source {
+        return Ok(());
+    }
+    let provider = config.credentials_provider().ok_or_else(|| {
+        CredentialsError::invalid_configuration("no source credentials provider is configured")
+    })?;
+    // The source providers cache the credentials, so this doesn't cost another lookup
+    if let Err(e) = credentials::ProvideCredentials::provide_credentials(provider).await {
+        let message = match source {
+            // Containers on EC2 can't reach IMDS through the default hop limit of IMDSv2, and the
+            // resulting timeout doesn't say so
+            Source::Imds => {
+                "the source credentials used to call STS could not be loaded from the instance \
+                 metadata service. When running in a container, check that the IMDSv2 hop limit \
+                 (HttpPutResponseHopLimit) of the instance is at least 2, and that IMDS is enabled"
+            }
+            _ => "the source credentials used to call STS could not be loaded",
+        };
+        return Err(CredentialsError::invalid_configuration(ContextError::new(
+            message, e,
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aws_types::credentials::future::ProvideCredentials;
+
+    /// A source provider that never finds credentials.
+    #[derive(Debug)]
+    struct NoCredentials;
+
+    impl credentials::ProvideCredentials for NoCredentials {
+        fn provide_credentials<'a>(&'a self) -> ProvideCredentials<'a>
+        where
+            Self: 'a,
+        {
+            ProvideCredentials::ready(Err(CredentialsError::not_loaded("nothing here")))
+        }
+    }
+
+    fn config(
+        region: Option<&'static str>,
+        credentials: Option<SharedCredentialsProvider>,
+    ) -> Config {
+        let mut config = Config::builder().region(region.map(Region::new));
+        config.set_credentials_provider(credentials);
+        config.build()
+    }
+
+    #[cfg(not(feature = "sts-global-endpoint"))]
+    #[tokio::test]
+    async fn a_missing_region_is_reported_as_such() {
+        let credentials = SharedCredentialsProvider::new(credentials::Credentials::new(
+            "AKID", "secret", None, None, "test",
+        ));
+        let error = check_sts_config(&config(None, Some(credentials)), &Source::Environment)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("no region is configured"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn missing_source_credentials_are_reported_as_such() {
+        let error = check_sts_config(&config(Some("eu-west-3"), None), &Source::Environment)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("no source credentials provider"),
+            "{}",
+            error
+        );
+
+        let credentials = SharedCredentialsProvider::new(NoCredentials);
+        let config = config(Some("eu-west-3"), Some(credentials));
+        let error = check_sts_config(&config, &Source::Environment)
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("could not be loaded"),
+            "{}",
+            error
+        );
+        // The hint about the hop limit is only given for IMDS
+        assert!(!error.to_string().contains("hop limit"), "{}", error);
+        let error = check_sts_config(&config, &Source::Imds).await.unwrap_err();
+        assert!(
+            error.to_string().contains("HttpPutResponseHopLimit"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn web_identities_need_no_source_credentials() {
+        check_sts_config(&config(Some("eu-west-3"), None), &Source::WebIdentity)
+            .await
+            .unwrap();
+        // Nor are they looked up when there is a provider
+        let credentials = SharedCredentialsProvider::new(NoCredentials);
+        let config = config(Some("eu-west-3"), Some(credentials));
+        check_sts_config(&config, &Source::WebIdentity)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+
+/// An error with a message explaining the context it occurred in.
+#[derive(Debug)]
+pub(crate) struct ContextError {
+    message: String,
+    source: Box<dyn Error + Send + Sync + 'static>,
+}
+
+impl ContextError {
+    pub(crate) fn new(
+        message: impl Into<String>,
+        source: impl Into<Box<dyn Error + Send + Sync + 'static>>,
+    ) -> Self {
+        Self {
+            message: message.into(),
+            source: source.into(),
+        }
+    }
+}
+
+impl Display for ContextError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.message, self.source)
+    }
+}
+
+impl Error for ContextError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.source.as_ref() as _)
+    }
+}
+
+/// Replaces secret values in messages.
+pub(crate) const REDACTED: &str = "** redacted **";
+
+/// An error whose message had a secret value removed.
+///
+/// The original error isn't kept as the source, as its message contains the secret.
+#[derive(Debug)]
+struct RedactedError(String);
+
+impl Display for RedactedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl Error for RedactedError {}
+
+/// Makes sure that `secret` doesn't appear in the message of `error` or of its sources.
+///
+/// If it does, the error is replaced with one carrying the same messages, with `secret` redacted.
+pub(crate) fn redact(
+    error: impl Into<Box<dyn Error + Send + Sync + 'static>>,
+    secret: Option<&str>,
+) -> Box<dyn Error + Send + Sync + 'static> {
+    let error = error.into();
+    let secret = match secret {
+        Some(secret) if !secret.is_empty() => secret,
+        _ => return error,
+    };
+    let mut messages = vec![error.to_string()];
+    let mut source = error.source();
+    while let Some(e) = source {
+        messages.push(e.to_string());
+        source = e.source();
+    }
+    let message = messages.join(": ");
+    if message.contains(secret) || format!("{:?}", error).contains(secret) {
+        Box::new(RedactedError(message.replace(secret, REDACTED)))
+    } else {
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_redacted_from_the_messages_of_the_sources() {
+        let error = ContextError::new("outer", "inner mentions s3cr3t");
+        let redacted = redact(error, Some("s3cr3t"));
+        assert_eq!(
+            redacted.to_string(),
+            format!(
+                "outer: inner mentions {}: inner mentions {}",
+                REDACTED, REDACTED
+            )
+        );
+        assert!(!format!("{:?}", redacted).contains("s3cr3t"));
+        // The message replaces the sources
+        assert!(redacted.source().is_none());
+    }
+
+    #[test]
+    fn errors_without_the_secret_are_kept() {
+        let redacted = redact(ContextError::new("outer", "inner"), Some("s3cr3t"));
+        assert_eq!(redacted.to_string(), "outer: inner");
+        assert!(redacted.source().is_some());
+    }
+
+    #[test]
+    fn nothing_is_redacted_without_a_secret() {
+        for secret in [None, Some("")] {
+            let redacted = redact("message", secret);
+            assert_eq!(redacted.to_string(), "message");
+        }
+    }
+}
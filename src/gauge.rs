@@ -0,0 +1,200 @@
+use crate::STSCredentialsProvider;
+use aws_types::credentials::CredentialsError;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Name of the gauge updated by [`spawn_expiry_gauge`]
+pub const EXPIRY_GAUGE_NAME: &str = "sts_credentials_seconds_until_expiry";
+/// Shortest interval between two updates of the gauge
+const MIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Periodically reports how long the cached credentials of `provider` remain valid.
+///
+/// Every `interval`, raised to 10 milliseconds if shorter, the gauge named [`EXPIRY_GAUGE_NAME`]
+/// is set to the number of seconds until the cached credentials expire, negative once they have
+/// expired. It carries a `role_arn` label with the ARN of the assumed role. The gauge isn't updated
+/// while the cache is empty.
+///
+/// The remaining time is measured with the time source of the provider. The gauge is registered
+/// with the global recorder of the `metrics` crate. Reading the expiry never triggers a refresh.
+///
+/// The task is started with the spawner of the provider and waits with its sleep implementation,
+/// see [`spawner`](crate::STSCredentialsProviderBuilder::spawner). It runs until
+/// [`ExpiryGauge::stop`] is called. Fails if the provider has no spawner.
+pub fn spawn_expiry_gauge(
+    provider: &STSCredentialsProvider,
+    interval: Duration,
+) -> Result<ExpiryGauge, CredentialsError> {
+    let spawner = provider.inner.spawner.clone().ok_or_else(|| {
+        CredentialsError::invalid_configuration("the provider has no spawner to run the gauge")
+    })?;
+    let provider = STSCredentialsProvider {
+        inner: provider.inner.clone(),
+    };
+    let gauge =
+        ::metrics::gauge!(EXPIRY_GAUGE_NAME, "role_arn" => provider.inner.params.role_arn.clone());
+    let stopped = Arc::new(AtomicBool::new(false));
+    let handle = ExpiryGauge {
+        stopped: stopped.clone(),
+    };
+    spawner.spawn(Box::pin(async move {
+        let interval = interval.max(MIN_INTERVAL);
+        while !stopped.load(Ordering::Acquire) {
+            if let Some(expiry) = provider.cached_expiry().await {
+                let seconds = match expiry.duration_since(provider.inner.time.now()) {
+                    Ok(remaining) => remaining.as_secs_f64(),
+                    Err(e) => -e.duration().as_secs_f64(),
+                };
+                gauge.set(seconds);
+            }
+            provider.inner.sleep.sleep(interval).await;
+        }
+    }));
+    Ok(handle)
+}
+
+/// Handle of the task started by [`spawn_expiry_gauge`].
+#[derive(Debug)]
+pub struct ExpiryGauge {
+    stopped: Arc<AtomicBool>,
+}
+
+impl ExpiryGauge {
+    /// Stops updating the gauge.
+    ///
+    /// The task ends at its next update. Dropping the handle doesn't stop it.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{manual_time, StubSts, DEFAULT_LIFETIME, ROLE_ARN};
+    use aws_types::os_shim_internal::TimeSource;
+    use metrics::{
+        Counter, Gauge, GaugeFn, Histogram, Key, KeyName, Metadata, Recorder, SharedString, Unit,
+    };
+    use std::sync::{Arc, Mutex};
+
+    /// Last value set on the gauge.
+    #[derive(Debug, Default)]
+    struct Value(Mutex<Option<f64>>);
+
+    impl GaugeFn for Value {
+        // The gauge is only set
+        fn increment(&self, _value: f64) {}
+
+        fn decrement(&self, _value: f64) {}
+
+        fn set(&self, value: f64) {
+            *self.0.lock().unwrap() = Some(value);
+        }
+    }
+
+    /// Records the key and the value of the single gauge registered.
+    #[derive(Debug, Default)]
+    struct SingleGauge {
+        key: Mutex<Option<Key>>,
+        value: Arc<Value>,
+    }
+
+    impl SingleGauge {
+        fn value(&self) -> Option<f64> {
+            *self.value.0.lock().unwrap()
+        }
+    }
+
+    impl Recorder for SingleGauge {
+        fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {
+        }
+
+        fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+        fn describe_histogram(
+            &self,
+            _key: KeyName,
+            _unit: Option<Unit>,
+            _description: SharedString,
+        ) {
+        }
+
+        fn register_counter(&self, _key: &Key, _metadata: &Metadata<'_>) -> Counter {
+            Counter::noop()
+        }
+
+        fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+            *self.key.lock().unwrap() = Some(key.clone());
+            Gauge::from_arc(self.value.clone())
+        }
+
+        fn register_histogram(&self, _key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+            Histogram::noop()
+        }
+    }
+
+    #[tokio::test]
+    async fn the_gauge_reports_the_time_until_the_expiry() {
+        let sts = StubSts::new();
+        let provider = sts.provider();
+        let recorder = SingleGauge::default();
+        let task = metrics::with_local_recorder(&recorder, || {
+            spawn_expiry_gauge(&provider, Duration::from_millis(10)).unwrap()
+        });
+
+        let key = recorder.key.lock().unwrap().clone().unwrap();
+        assert_eq!(key.name(), EXPIRY_GAUGE_NAME);
+        assert!(key
+            .labels()
+            .any(|label| label.key() == "role_arn" && label.value() == ROLE_ARN));
+        // Not updated while the cache is empty
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(recorder.value(), None);
+
+        provider.get_credentials().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let seconds = recorder.value().unwrap();
+        assert!(seconds <= DEFAULT_LIFETIME.as_secs_f64(), "{}", seconds);
+        assert!(
+            seconds > DEFAULT_LIFETIME.as_secs_f64() - 5.0,
+            "{}",
+            seconds
+        );
+        task.stop();
+    }
+
+    #[tokio::test]
+    async fn zero_intervals_are_raised_to_the_minimum() {
+        let sts = StubSts::new();
+        let provider = sts.provider();
+        provider.get_credentials().await.unwrap();
+        let recorder = SingleGauge::default();
+        let task = metrics::with_local_recorder(&recorder, || {
+            spawn_expiry_gauge(&provider, Duration::ZERO).unwrap()
+        });
+        tokio::time::sleep(MIN_INTERVAL * 3).await;
+        assert!(recorder.value().is_some());
+        task.stop();
+    }
+
+    #[tokio::test]
+    async fn the_gauge_follows_the_time_source_of_the_provider() {
+        let mut clock = manual_time();
+        let sts = StubSts::with_time(TimeSource::manual(&clock));
+        let provider = sts.provider();
+        provider.get_credentials().await.unwrap();
+        let recorder = SingleGauge::default();
+        let task = metrics::with_local_recorder(&recorder, || {
+            spawn_expiry_gauge(&provider, Duration::from_millis(10)).unwrap()
+        });
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(recorder.value(), Some(DEFAULT_LIFETIME.as_secs_f64()));
+        clock.advance(DEFAULT_LIFETIME + Duration::from_secs(60));
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(recorder.value(), Some(-60.0));
+        task.stop();
+    }
+}
@@ -0,0 +1,206 @@
+use crate::error::{ContextError, REDACTED};
+use aws_types::credentials::CredentialsError;
+use std::env;
+use std::fmt::{self, Debug, Formatter};
+use std::future::Future;
+use std::path::PathBuf;
+use std::pin::Pin;
+
+/// Future returned by [`IdentityTokenProvider::token`].
+pub type TokenFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<IdentityToken, CredentialsError>> + Send + 'a>>;
+
+/// A token exchanged for credentials with `AssumeRoleWithWebIdentity`, such as an OIDC ID token.
+///
+/// It grants access to the role, so `Debug` doesn't show it.
+#[derive(Clone)]
+pub struct IdentityToken(String);
+
+impl IdentityToken {
+    pub fn new(token: impl Into<String>) -> Self {
+        Self(token.into())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for IdentityToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("IdentityToken").field(&REDACTED).finish()
+    }
+}
+
+/// Source of the tokens the role is assumed with through `AssumeRoleWithWebIdentity`.
+///
+/// Set with [`web_identity`](crate::STSCredentialsProviderBuilder::web_identity), it lets any
+/// federated flow (OIDC, a custom token service...) drive the provider. The provider caches the
+/// credentials, not the tokens: `token` is called before each `AssumeRoleWithWebIdentity` call,
+/// including retries and calls for fallback roles, possibly from several tasks at once.
+///
+/// The token returned must be valid when it is returned. Tokens are usually short-lived and
+/// rotated, so implementations should read or refresh them on each call, or at least when they
+/// are close to their expiry, rather than keep the one obtained at startup.
+pub trait IdentityTokenProvider: Debug + Send + Sync {
+    /// Returns a currently valid token.
+    fn token(&self) -> TokenFuture<'_>;
+}
+
+/// Reads the token from a file on each call.
+///
+/// This suits tokens rotated on disk by the platform, such as the projected service account
+/// tokens of EKS, whose path is in `AWS_WEB_IDENTITY_TOKEN_FILE`. Surrounding whitespace is
+/// trimmed.
+#[derive(Clone, Debug)]
+pub struct FileTokenProvider {
+    path: PathBuf,
+}
+
+impl FileTokenProvider {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+}
+
+impl IdentityTokenProvider for FileTokenProvider {
+    fn token(&self) -> TokenFuture<'_> {
+        Box::pin(async move {
+            // Token files are small, so they are read without the runtime-specific async APIs
+            let token = std::fs::read_to_string(&self.path).map_err(|e| {
+                CredentialsError::not_loaded(ContextError::new(
+                    format!(
+                        "could not read the identity token from {}",
+                        self.path.display()
+                    ),
+                    e,
+                ))
+            })?;
+            non_empty(token.trim(), || self.path.display().to_string())
+        })
+    }
+}
+
+/// Reads the token from an environment variable on each call.
+///
+/// Surrounding whitespace is trimmed.
+#[derive(Clone, Debug)]
+pub struct EnvTokenProvider {
+    name: String,
+}
+
+impl EnvTokenProvider {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self { name: name.into() }
+    }
+}
+
+impl IdentityTokenProvider for EnvTokenProvider {
+    fn token(&self) -> TokenFuture<'_> {
+        Box::pin(async move {
+            let token = env::var(&self.name).map_err(|e| {
+                CredentialsError::not_loaded(ContextError::new(
+                    format!(
+                        "could not read the identity token from environment variable {}",
+                        self.name
+                    ),
+                    e,
+                ))
+            })?;
+            non_empty(token.trim(), || {
+                format!("environment variable {}", self.name)
+            })
+        })
+    }
+}
+
+/// Returns the token, or an error naming where it was read from if it is empty.
+fn non_empty(
+    token: &str,
+    origin: impl FnOnce() -> String,
+) -> Result<IdentityToken, CredentialsError> {
+    match token.is_empty() {
+        true => Err(CredentialsError::not_loaded(format!(
+            "the identity token read from {} is empty",
+            origin()
+        ))),
+        false => Ok(IdentityToken::new(token)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the path of a file named after the test, in a directory of this process.
+    fn token_file(name: &str, content: Option<&str>) -> PathBuf {
+        let dir = env::temp_dir().join(format!("sts-caching-provider-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        match content {
+            Some(content) => std::fs::write(&path, content).unwrap(),
+            None => {
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+        path
+    }
+
+    #[tokio::test]
+    async fn file_tokens_are_read_and_trimmed() {
+        let path = token_file("trimmed", Some("  token\n"));
+        let token = FileTokenProvider::new(path).token().await.unwrap();
+        assert_eq!(token.as_str(), "token");
+    }
+
+    #[tokio::test]
+    async fn missing_or_empty_token_files_are_errors() {
+        let path = token_file("missing", None);
+        let error = FileTokenProvider::new(&path).token().await.unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("could not read the identity token"),
+            "{}",
+            error
+        );
+
+        let path = token_file("empty", Some(" \n"));
+        let error = FileTokenProvider::new(&path).token().await.unwrap_err();
+        let message = format!("the identity token read from {} is empty", path.display());
+        assert!(error.to_string().contains(&message), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn environment_tokens_are_read_and_trimmed() {
+        env::set_var("STS_CACHING_PROVIDER_TEST_TOKEN", "token \n");
+        let provider = EnvTokenProvider::new("STS_CACHING_PROVIDER_TEST_TOKEN");
+        assert_eq!(provider.token().await.unwrap().as_str(), "token");
+    }
+
+    #[tokio::test]
+    async fn unset_or_empty_environment_tokens_are_errors() {
+        let provider = EnvTokenProvider::new("STS_CACHING_PROVIDER_TEST_UNSET_TOKEN");
+        let error = provider.token().await.unwrap_err();
+        assert!(
+            error.to_string().contains(
+                "could not read the identity token from environment variable \
+                 STS_CACHING_PROVIDER_TEST_UNSET_TOKEN"
+            ),
+            "{}",
+            error
+        );
+
+        env::set_var("STS_CACHING_PROVIDER_TEST_EMPTY_TOKEN", "");
+        let provider = EnvTokenProvider::new("STS_CACHING_PROVIDER_TEST_EMPTY_TOKEN");
+        let error = provider.token().await.unwrap_err();
+        assert!(error.to_string().contains("is empty"), "{}", error);
+    }
+
+    #[test]
+    fn tokens_are_redacted() {
+        let debug = format!("{:?}", IdentityToken::new("secret-token"));
+        assert!(!debug.contains("secret-token"), "{}", debug);
+        assert!(debug.contains(REDACTED), "{}", debug);
+    }
+}
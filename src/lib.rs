@@ -1,28 +1,463 @@
+use arc_swap::ArcSwapOption;
+use aws_sdk_sts::error::AssumeRoleError;
+use aws_sdk_sts::model::Tag;
+use aws_sdk_sts::output::AssumeRoleOutput;
+use aws_sdk_sts::{Client, SdkError};
+use aws_smithy_async::future::timeout::Timeout;
+use aws_smithy_async::rt::sleep::AsyncSleep;
 use aws_types::credentials::future::ProvideCredentials;
-use aws_types::credentials::CredentialsError;
+use aws_types::credentials::{CredentialsError, SharedCredentialsProvider};
+use aws_types::os_shim_internal::TimeSource;
 use aws_types::{credentials, Credentials};
-use std::time::{Duration, SystemTime};
+use client::StsClient;
+use error::{ContextError, REDACTED};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Debug, Formatter};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::iter;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tokio::sync::RwLock;
-use tracing::debug;
+use tracing::{debug, info, warn, Instrument};
+
+mod builder;
+mod client;
+mod error;
+#[cfg(feature = "metrics")]
+mod gauge;
+mod identity;
+#[cfg(feature = "otel")]
+mod otel;
+mod rate_limit;
+mod scoped;
+mod spawn;
+#[cfg(test)]
+mod testing;
+#[cfg(test)]
+mod tests;
+
+pub use builder::{BuildError, FallbackRole, STSCredentialsProviderBuilder};
+#[cfg(feature = "metrics")]
+pub use gauge::{spawn_expiry_gauge, ExpiryGauge, EXPIRY_GAUGE_NAME};
+pub use identity::{
+    EnvTokenProvider, FileTokenProvider, IdentityToken, IdentityTokenProvider, TokenFuture,
+};
+pub use rate_limit::RateLimiter;
+pub use scoped::ScopedStats;
+#[cfg(feature = "rt-tokio")]
+pub use spawn::TokioSpawn;
+pub use spawn::{Spawn, Task};
+
+/// Delay before the first retry of [`STSCredentialsProvider::wait_for_credentials`]
+const INITIAL_WAIT_BACKOFF: Duration = Duration::from_millis(100);
+/// Maximum delay between retries of [`STSCredentialsProvider::wait_for_credentials`]
+const MAX_WAIT_BACKOFF: Duration = Duration::from_secs(2);
+/// Session duration applied by STS when none is requested
+const DEFAULT_SESSION_DURATION: Duration = Duration::from_secs(3600);
+/// Maximum delay before returning credentials that aren't valid yet according to the local clock
+const MAX_NOT_BEFORE_WAIT: Duration = Duration::from_secs(5);
+/// Maximum length of a role session name accepted by STS
+const MAX_SESSION_NAME_LENGTH: usize = 64;
+/// Number of hexadecimal digits of the suffix appended to unique session names
+const SESSION_NAME_SUFFIX_LENGTH: usize = 8;
+/// Number of load latencies kept for [`STSCredentialsProvider::suggested_cache_timeout`]
+const LATENCY_SAMPLES: usize = 100;
+/// Margin added to the observed latency by [`STSCredentialsProvider::suggested_cache_timeout`]
+const SUGGESTED_CACHE_TIMEOUT_MARGIN: Duration = Duration::from_secs(30);
+/// How much shorter than requested a session may be before it is reported
+const GRANTED_DURATION_TOLERANCE: Duration = Duration::from_secs(60);
+/// How long credentials already expired when obtained are returned before calling STS again
+const EXPIRED_LOAD_HOLD: Duration = Duration::from_secs(5);
 
 /// A caching CredentialsProvider that retrieves credentials from STS.
 ///
-/// STS is queried using environment credentials.
+/// STS is queried using environment credentials, unless a source profile is set on the builder.
 ///
 /// The credentials are retrieved via an `AssumeRole` call. The fields of the struct reflect the
 /// parameters for that call.
 ///
 /// The `cache_timeout` field represents how many seconds in the future the temporary token must be
 /// valid before being considered stale.
+///
+/// More options are available through [`STSCredentialsProvider::builder`].
+///
+/// Fallback roles can be set on the builder, in which case they are tried in order when the primary
+/// role can't be assumed, whatever the reason. The role that was assumed is remembered and tried
+/// first on the next refresh, the other roles only being tried again if it fails. So once a
+/// fallback role has been assumed, the primary role is only tried again when that role fails. If no
+/// role can be assumed, the error for the primary role is returned, and the others are logged.
+///
+/// If STS reports that the source credentials have expired, they are reloaded and the `AssumeRole`
+/// call is retried. This is only attempted once per refresh.
+///
+/// Note that STS treats `source_identity` as immutable: once a session carries a source identity,
+/// any role assumed from it keeps that value and an `AssumeRole` call setting a different one is
+/// rejected. When the source credentials are themselves an assumed-role session, `source_identity`
+/// must either be unset or match the one already carried by that session.
 #[derive(Debug)]
 pub struct STSCredentialsProvider {
+    inner: Arc<Inner>,
+}
+
+/// The state of the provider, shared with background refreshes.
+#[derive(Debug)]
+struct Inner {
+    params: AssumeRoleParams,
+    cred_cache: RwLock<Option<CachedCredentials>>,
+    /// Copy of the content of `cred_cache`, read without locking
+    ///
+    /// It is only written through [`Inner::store`], while holding the write lock of `cred_cache`.
+    /// It exists for the reads that must not wait for the lock, not for speed: with
+    /// [`InFlightPolicy::FailFast`], a refresh takes the lock with `try_write`, which plain reads
+    /// holding it would make fail, and [`prune`](STSCredentialsProvider::prune) and
+    /// [`get_scoped`](STSCredentialsProvider::get_scoped) read the caches of scoped providers from
+    /// synchronous code. Everything that only reads the cache goes through this copy, so that the
+    /// lock is only ever held by refreshes.
+    current: ArcSwapOption<CachedCredentials>,
+    cache_timeout: Duration,
+    refresh_ahead: Duration,
+    delivery_cushion: Duration,
+    refreshing: AtomicBool,
+    client: Arc<StsClient>,
+    /// How many times freshly loaded credentials were already stale
+    stale_loads: AtomicU64,
+    /// Roles tried in order when the primary one can't be assumed
+    fallbacks: Vec<AssumeRoleParams>,
+    /// Index of the role that was assumed last, 0 being the primary one
+    preferred_role: AtomicUsize,
+    short_lifetime: ShortLifetimePolicy,
+    clock_skew: ClockSkewPolicy,
+    /// Whether credentials with a short lifetime have already been reported
+    short_lifetime_logged: AtomicBool,
+    sleep: Arc<dyn AsyncSleep>,
+    /// Runs the background refreshes, always set when `refresh_ahead` is
+    spawner: Option<Arc<dyn Spawn>>,
+    /// Clock deciding whether the credentials are stale
+    time: TimeSource,
+    clear_cache_on_error: bool,
+    /// Whether a unique suffix is appended to the session name of each `AssumeRole` call
+    unique_session_names: bool,
+    /// Number of session names generated so far, mixed into the suffixes
+    session_counter: AtomicU64,
+    /// Durations of the last successful loads, oldest first
+    load_latencies: Mutex<VecDeque<Duration>>,
+    /// Identifiers selectable with [`STSCredentialsProvider::for_environment`]
+    environments: HashMap<String, EnvironmentIds>,
+    /// Whether a session shorter than requested has already been reported
+    short_grant_logged: AtomicBool,
+    post_load: Option<PostLoad>,
+    session_token: SessionTokenPolicy,
+    lock_timeout: Option<Duration>,
+    in_flight: InFlightPolicy,
+    /// Maximum number of `AssumeRole` calls the provider may make
+    max_lifetime_calls: Option<u64>,
+    /// Number of `AssumeRole` calls made so far, shared with the derived providers
+    sts_calls: Arc<AtomicU64>,
+    rate_limiter: Option<RateLimiter>,
+    /// Source of the tokens the role is assumed with, instead of the source credentials
+    identity_tokens: Option<Arc<dyn IdentityTokenProvider>>,
+    /// Providers returned by [`STSCredentialsProvider::get_scoped`], by parameters
+    scoped: Mutex<scoped::ScopedCache>,
+    /// How long a scoped provider may go unused before it is evicted
+    scoped_idle_ttl: Duration,
+    /// How many scoped providers are kept, the least recently used being evicted first
+    scoped_max_entries: usize,
+    /// Name given to the credentials, shown in the diagnostics of the SDK
+    provider_name: &'static str,
+    /// Whether the source credentials are returned instead of assuming the role
+    passthrough: bool,
+}
+
+/// Signature of the hook set with [`STSCredentialsProviderBuilder::post_load`].
+type PostLoadFn =
+    dyn Fn(Credentials, &AssumeRoleOutput) -> Result<Credentials, CredentialsError> + Send + Sync;
+
+/// Hook called on the credentials obtained from STS before they are cached.
+#[derive(Clone)]
+struct PostLoad(Arc<PostLoadFn>);
+
+impl Debug for PostLoad {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.write_str("PostLoad")
+    }
+}
+
+/// The parameters sent with the `AssumeRole` call.
+///
+/// Cached credentials carry a copy of the parameters they were obtained with, so that they are
+/// never returned for a different configuration.
+///
+/// Unset optional parameters are omitted from the request, never sent as empty values, which STS
+/// may treat differently. Empty strings and empty collections count as unset.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct AssumeRoleParams {
     role_arn: String,
     external_id: Option<String>,
     source_identity: Option<String>,
-    cred_cache: RwLock<Option<Credentials>>,
     session_name: Option<String>,
     session_duration: Option<i32>,
-    cache_timeout: u64,
+    /// Session tags, as `(key, value)` pairs sorted by key
+    tags: Vec<(String, String)>,
+    /// Session policy, further restricting the permissions of the session
+    policy: Option<String>,
+}
+
+impl AssumeRoleParams {
+    /// Returns the external id to send, if any.
+    fn sts_external_id(&self) -> Option<String> {
+        non_empty(&self.external_id)
+    }
+
+    /// Returns the source identity to send, if any.
+    fn sts_source_identity(&self) -> Option<String> {
+        non_empty(&self.source_identity)
+    }
+
+    /// Returns the session tags in the form expected by the client, if any.
+    fn sts_tags(&self) -> Option<Vec<Tag>> {
+        if self.tags.is_empty() {
+            return None;
+        }
+        let tags = self
+            .tags
+            .iter()
+            .map(|(key, value)| Tag::builder().key(key).value(value).build())
+            .collect();
+        Some(tags)
+    }
+}
+
+impl Debug for AssumeRoleParams {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AssumeRoleParams")
+            .field("role_arn", &self.role_arn)
+            .field("external_id", &redacted(&self.external_id))
+            .field("source_identity", &self.source_identity)
+            .field("session_name", &self.session_name)
+            .field("session_duration", &self.session_duration)
+            .field("tags", &self.tags)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+/// Parameters replaced by [`STSCredentialsProvider::with_overrides`].
+///
+/// Each parameter that is set replaces the corresponding one of the original provider, the
+/// others are kept as-is. The session tags are replaced as a whole, and unlike those set on the
+/// builder, they aren't checked before being sent to STS.
+///
+/// ```
+/// use aws_sdk_sts_caching_provider::Overrides;
+///
+/// let overrides = Overrides::default()
+///     .session_name("tenant-42")
+///     .tag("tenant", "42");
+/// ```
+#[derive(Clone, Default)]
+#[non_exhaustive]
+pub struct Overrides {
+    pub role_arn: Option<String>,
+    pub external_id: Option<String>,
+    pub source_identity: Option<String>,
+    pub session_name: Option<String>,
+    pub session_duration: Option<i32>,
+    pub tags: Option<HashMap<String, String>>,
+    pub policy: Option<String>,
+}
+
+impl Overrides {
+    /// Replaces the ARN of the role to assume.
+    pub fn role_arn(mut self, role_arn: impl Into<String>) -> Self {
+        self.role_arn = Some(role_arn.into());
+        self
+    }
+
+    /// Replaces the external id.
+    pub fn external_id(mut self, external_id: impl Into<String>) -> Self {
+        self.external_id = Some(external_id.into());
+        self
+    }
+
+    /// Replaces the source identity.
+    pub fn source_identity(mut self, source_identity: impl Into<String>) -> Self {
+        self.source_identity = Some(source_identity.into());
+        self
+    }
+
+    /// Replaces the role session name.
+    pub fn session_name(mut self, session_name: impl Into<String>) -> Self {
+        self.session_name = Some(session_name.into());
+        self
+    }
+
+    /// Replaces the requested session duration, in seconds.
+    pub fn session_duration(mut self, session_duration: i32) -> Self {
+        self.session_duration = Some(session_duration);
+        self
+    }
+
+    /// Adds a session tag, the tags of the original provider being replaced by those added.
+    pub fn tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.tags
+            .get_or_insert_with(HashMap::new)
+            .insert(key.into(), value.into());
+        self
+    }
+
+    /// Replaces the session policy.
+    pub fn policy(mut self, policy: impl Into<String>) -> Self {
+        self.policy = Some(policy.into());
+        self
+    }
+}
+
+impl Debug for Overrides {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Overrides")
+            .field("role_arn", &self.role_arn)
+            .field("external_id", &redacted(&self.external_id))
+            .field("source_identity", &self.source_identity)
+            .field("session_name", &self.session_name)
+            .field("session_duration", &self.session_duration)
+            .field("tags", &self.tags)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+/// External id and source identity used for an environment.
+///
+/// See [`STSCredentialsProviderBuilder::environment`].
+#[derive(Clone, Default)]
+pub struct EnvironmentIds {
+    pub external_id: Option<String>,
+    pub source_identity: Option<String>,
+}
+
+impl Debug for EnvironmentIds {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EnvironmentIds")
+            .field("external_id", &redacted(&self.external_id))
+            .field("source_identity", &self.source_identity)
+            .finish()
+    }
+}
+
+/// What to do when STS returns credentials whose lifetime is too short for the configured margins.
+///
+/// This happens when `cache_timeout` (plus the delivery cushion) is longer than the session
+/// duration, e.g. a role with a 15 minutes maximum session duration and a 20 minutes cache
+/// timeout. Left alone, such credentials would be stale as soon as they are obtained, and each read
+/// would call STS again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ShortLifetimePolicy {
+    /// Serve the credentials, refreshing them once half of their remaining lifetime has elapsed.
+    ///
+    /// A warning is logged the first time this happens.
+    #[default]
+    Serve,
+    /// Return an error explaining the misconfiguration.
+    Fail,
+}
+
+/// How the session token returned by STS is handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SessionTokenPolicy {
+    /// Pass the session token on as returned by STS, if any.
+    #[default]
+    Passthrough,
+    /// Fail if STS returns no session token.
+    Require,
+    /// Never include the session token in the credentials.
+    ///
+    /// Credentials obtained through `AssumeRole` can't be used without their session token, so
+    /// this is only useful when they are forwarded to a system that obtains it separately.
+    Omit,
+}
+
+/// What to do when credentials obtained from STS were issued after the time of the local clock.
+///
+/// This means that the local clock is behind that of STS, and AWS may reject the credentials until
+/// it catches up. The issue time is inferred from the expiry, so only sessions shorter than
+/// requested go unnoticed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ClockSkewPolicy {
+    /// Wait until the issue time if it is at most 5 seconds away, serve the credentials with a
+    /// warning otherwise, as there is no telling whether the clock will catch up.
+    #[default]
+    Wait,
+    /// Request new credentials right away, once, and serve them with a warning if they were also
+    /// issued in the future.
+    ///
+    /// This helps when the skew comes from a hiccup of the clock rather than a steady offset.
+    Refresh,
+    /// Serve the credentials right away, with a warning.
+    Serve,
+}
+
+/// What reads finding stale credentials do while another task is already refreshing them.
+///
+/// Refreshes are single-flight, so only one `AssumeRole` call is made however many reads find the
+/// credentials stale.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InFlightPolicy {
+    /// Wait for the refresh to complete and return its result.
+    ///
+    /// Reads get fresh credentials, but may be delayed by a slow STS call. The wait can be bounded
+    /// with [`STSCredentialsProviderBuilder::lock_timeout`].
+    #[default]
+    Wait,
+    /// Return right away with the stale credentials if they haven't expired yet, or an error.
+    ///
+    /// This suits latency-sensitive callers which would rather fail over than wait. The stale
+    /// credentials are closer to their expiry than `cache_timeout` allows, so they may expire
+    /// before they are used.
+    FailFast,
+}
+
+/// Credentials returned by [`STSCredentialsProvider::get_credentials_with_metadata`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct AssumedCredentials {
+    pub credentials: Credentials,
+    pub expiry: SystemTime,
+    /// The ARN of the assumed-role session, as returned by STS
+    pub assumed_role_arn: Option<String>,
+    /// The role session name configured on the provider
+    pub session_name: Option<String>,
+}
+
+/// Credentials as stored in the cache, along with the metadata of their call.
+#[derive(Clone, Debug)]
+struct CachedCredentials {
+    credentials: Credentials,
+    assumed_role_arn: Option<String>,
+    /// Margin replacing the configured ones for credentials with a short lifetime
+    reduced_margin: Option<Duration>,
+    /// Time until which the credentials are returned whatever their expiry
+    ///
+    /// Only set for credentials that had already expired when they were obtained.
+    hold_until: Option<SystemTime>,
+    /// When the credentials were requested, according to the local clock
+    fetched_at: SystemTime,
+}
+
+impl CachedCredentials {
+    /// Returns the time between the request of the credentials and their expiry.
+    fn granted_lifetime(&self) -> Duration {
+        self.credentials
+            .expiry()
+            .unwrap()
+            .duration_since(self.fetched_at)
+            .unwrap_or_default()
+    }
 }
 
 impl STSCredentialsProvider {
@@ -34,80 +469,1252 @@ impl STSCredentialsProvider {
         session_duration: Option<i32>,
         cache_timeout: u64,
     ) -> Self {
+        let mut builder = Self::builder(role_arn).cache_timeout(Duration::from_secs(cache_timeout));
+        if let Some(external_id) = external_id {
+            builder = builder.external_id(external_id);
+        }
+        if let Some(source_identity) = source_identity {
+            builder = builder.source_identity(source_identity);
+        }
+        if let Some(session_name) = session_name {
+            builder = builder.session_name(session_name);
+        }
+        if let Some(session_duration) = session_duration {
+            builder = builder.session_duration(session_duration);
+        }
+        builder
+            .build()
+            .expect("options set by new are always consistent")
+    }
+
+    /// Returns a builder for a provider assuming the role `role_arn`.
+    pub fn builder(role_arn: &str) -> STSCredentialsProviderBuilder {
+        STSCredentialsProviderBuilder::new(role_arn)
+    }
+
+    /// Returns a provider configured from environment variables.
+    ///
+    /// The following variables are read, empty values being treated as unset:
+    ///
+    /// * `STS_ROLE_ARN`: the ARN of the role to assume. This is the only required variable.
+    /// * `STS_EXTERNAL_ID`: the external id.
+    /// * `STS_SESSION_NAME`: the role session name.
+    /// * `STS_SESSION_DURATION`: the requested session duration, as a number of seconds. STS
+    ///   defaults to one hour if unset.
+    /// * `STS_CACHE_TIMEOUT`: the cache timeout, as a number of seconds. Defaults to 60.
+    ///
+    /// The source credentials and the region are still resolved as usual, from the standard AWS
+    /// variables and config files.
+    pub fn from_env() -> Result<Self, BuildError> {
+        STSCredentialsProviderBuilder::from_env()?.build()
+    }
+
+    /// Returns a provider with some of the `AssumeRole` parameters replaced.
+    ///
+    /// The new provider shares the STS client of this one, along with the source credentials it
+    /// holds, which makes it cheap to create many scoped variants. Its cache is its own: the
+    /// credentials obtained by either provider are never returned by the other.
+    pub fn with_overrides(&self, overrides: Overrides) -> Self {
+        self.with_params(self.overridden_params(overrides))
+    }
+
+    /// Returns the parameters of this provider, with those that are set in `overrides` replaced.
+    fn overridden_params(&self, overrides: Overrides) -> AssumeRoleParams {
+        let params = &self.inner.params;
+        AssumeRoleParams {
+            role_arn: overrides
+                .role_arn
+                .unwrap_or_else(|| params.role_arn.clone()),
+            external_id: overrides.external_id.or_else(|| params.external_id.clone()),
+            source_identity: overrides
+                .source_identity
+                .or_else(|| params.source_identity.clone()),
+            session_name: overrides
+                .session_name
+                .or_else(|| params.session_name.clone()),
+            session_duration: overrides.session_duration.or(params.session_duration),
+            tags: match overrides.tags {
+                Some(tags) => {
+                    let mut tags: Vec<_> = tags.into_iter().collect();
+                    tags.sort();
+                    tags
+                }
+                None => params.tags.clone(),
+            },
+            policy: overrides.policy.or_else(|| params.policy.clone()),
+        }
+    }
+
+    /// Returns a provider using the external id and source identity of the given environment.
+    ///
+    /// The environments are declared with [`STSCredentialsProviderBuilder::environment`]. Both
+    /// identifiers are replaced, even if unset for the environment. As with
+    /// [`with_overrides`](Self::with_overrides), the new provider shares the STS client of this
+    /// one but has its own cache, so credentials obtained for one environment are never returned
+    /// for another.
+    ///
+    /// Returns `None` if the environment wasn't declared.
+    pub fn for_environment(&self, environment: &str) -> Option<Self> {
+        let ids = self.inner.environments.get(environment)?;
+        let params = AssumeRoleParams {
+            external_id: ids.external_id.clone(),
+            source_identity: ids.source_identity.clone(),
+            ..self.inner.params.clone()
+        };
+        Some(self.with_params(params))
+    }
+
+    /// Returns a provider with the given parameters, sharing the settings and client of this one.
+    ///
+    /// The fallback roles are given the session tags and policy of the new parameters, so that
+    /// they are never assumed with broader permissions than the primary role.
+    fn with_params(&self, params: AssumeRoleParams) -> Self {
+        let fallbacks = self
+            .inner
+            .fallbacks
+            .iter()
+            .map(|role| AssumeRoleParams {
+                tags: params.tags.clone(),
+                policy: params.policy.clone(),
+                ..role.clone()
+            })
+            .collect();
         Self {
-            role_arn: role_arn.to_string(),
-            external_id: external_id.map(String::from),
-            source_identity: source_identity.map(String::from),
-            cred_cache: RwLock::new(None),
-            session_name: session_name.map(String::from),
-            session_duration,
-            cache_timeout,
+            inner: Arc::new(Inner {
+                params,
+                cred_cache: RwLock::new(None),
+                current: ArcSwapOption::empty(),
+                cache_timeout: self.inner.cache_timeout,
+                refresh_ahead: self.inner.refresh_ahead,
+                delivery_cushion: self.inner.delivery_cushion,
+                refreshing: AtomicBool::new(false),
+                client: self.inner.client.clone(),
+                stale_loads: AtomicU64::new(0),
+                fallbacks,
+                preferred_role: AtomicUsize::new(0),
+                short_lifetime: self.inner.short_lifetime,
+                clock_skew: self.inner.clock_skew,
+                short_lifetime_logged: AtomicBool::new(false),
+                sleep: self.inner.sleep.clone(),
+                spawner: self.inner.spawner.clone(),
+                time: self.inner.time.clone(),
+                clear_cache_on_error: self.inner.clear_cache_on_error,
+                unique_session_names: self.inner.unique_session_names,
+                session_counter: AtomicU64::new(0),
+                load_latencies: Mutex::new(VecDeque::with_capacity(LATENCY_SAMPLES)),
+                environments: self.inner.environments.clone(),
+                short_grant_logged: AtomicBool::new(false),
+                post_load: self.inner.post_load.clone(),
+                session_token: self.inner.session_token,
+                lock_timeout: self.inner.lock_timeout,
+                in_flight: self.inner.in_flight,
+                max_lifetime_calls: self.inner.max_lifetime_calls,
+                sts_calls: self.inner.sts_calls.clone(),
+                rate_limiter: self.inner.rate_limiter.clone(),
+                identity_tokens: self.inner.identity_tokens.clone(),
+                scoped: Mutex::default(),
+                scoped_idle_ttl: self.inner.scoped_idle_ttl,
+                scoped_max_entries: self.inner.scoped_max_entries,
+                provider_name: self.inner.provider_name,
+                passthrough: self.inner.passthrough,
+            }),
         }
     }
 
-    /// Returns the stored credentials if they are valid
-    /// The credentials are valid iff
-    /// * they're not None
-    /// * they expire at least `cache_timeout` seconds in the future
-    async fn stored_credentials(&self) -> Option<Credentials> {
-        let lock = self.cred_cache.read().await;
-        if let Some(c) = lock.as_ref() {
-            let min_expiry_time = SystemTime::now() + Duration::from_secs(self.cache_timeout);
-            let expiration = c.expiry().unwrap();
-            if expiration > min_expiry_time {
-                return Some(c.clone());
+    /// Wraps the provider in the shared type expected by the SDK configuration.
+    ///
+    /// ```no_run
+    /// # use aws_sdk_sts_caching_provider::STSCredentialsProvider;
+    /// # async fn load(provider: STSCredentialsProvider) {
+    /// let config = aws_config::from_env()
+    ///     .credentials_provider(provider.into_shared())
+    ///     .load()
+    ///     .await;
+    /// # }
+    /// ```
+    pub fn into_shared(self) -> SharedCredentialsProvider {
+        SharedCredentialsProvider::new(self)
+    }
+
+    /// Returns the STS client the provider assumes the role with, building it if needed.
+    ///
+    /// This lets other STS calls, e.g. `GetCallerIdentity` for diagnostics, use the same
+    /// configuration and region without building another client. The client is shared with the
+    /// provider and its derived providers. Note that it authenticates with the **source**
+    /// credentials, not with the assumed role:
+    ///
+    /// ```no_run
+    /// # use aws_sdk_sts_caching_provider::STSCredentialsProvider;
+    /// # async fn check(provider: STSCredentialsProvider) {
+    /// let client = provider.sts_client().await.unwrap();
+    /// // The identity the role is assumed from
+    /// let identity = client.get_caller_identity().send().await.unwrap();
+    /// # }
+    /// ```
+    pub async fn sts_client(&self) -> Result<Client, CredentialsError> {
+        self.inner.client.get().await
+    }
+
+    /// Returns the access key id of the cached credentials, if any.
+    ///
+    /// This is meant for correlating local logs with CloudTrail events. It never triggers a
+    /// refresh, and the returned credentials may be stale. The secret access key and the session
+    /// token are intentionally never exposed by this method.
+    pub async fn current_access_key_id(&self) -> Option<String> {
+        self.inner
+            .current
+            .load()
+            .as_deref()
+            .map(|c| c.credentials.access_key_id().to_string())
+    }
+
+    /// Returns the account id of the role the cached credentials were obtained for, if any.
+    ///
+    /// The account id is extracted from the assumed-role ARN returned by STS. The `Credentials`
+    /// type of the SDK version this crate depends on has no room for it, so it is only exposed
+    /// here. This never triggers a refresh.
+    pub async fn account_id(&self) -> Option<String> {
+        self.inner
+            .current
+            .load()
+            .as_deref()
+            .and_then(|c| c.assumed_role_arn.as_deref())
+            .and_then(account_id_from_arn)
+            .map(String::from)
+    }
+
+    /// Returns when the cached credentials were requested from STS, if any are cached.
+    ///
+    /// The time is taken from the local clock when the `AssumeRole` call is sent, so it can be
+    /// compared with other local times to get the age of the credentials, whatever the skew with
+    /// the clock of STS. This never triggers a refresh.
+    pub async fn issued_at(&self) -> Option<SystemTime> {
+        self.inner.current.load().as_deref().map(|c| c.fetched_at)
+    }
+
+    /// Returns the lifetime STS granted to the cached credentials, if any.
+    ///
+    /// This is the time between the request and the expiry of the credentials, which may be
+    /// shorter than the requested session duration, e.g. with role chaining. It never triggers a
+    /// refresh.
+    pub async fn granted_lifetime(&self) -> Option<Duration> {
+        self.inner
+            .current
+            .load()
+            .as_deref()
+            .map(CachedCredentials::granted_lifetime)
+    }
+
+    /// Returns a `cache_timeout` suited to the latency of STS observed so far, if any.
+    ///
+    /// This is the 99th percentile of the duration of the last successful calls to STS, plus a 30
+    /// seconds margin for the use of the credentials by the caller. It is only meant as guidance
+    /// when tuning `cache_timeout`, which is not changed. Returns `None` until credentials have
+    /// been loaded.
+    pub fn suggested_cache_timeout(&self) -> Option<Duration> {
+        let mut latencies: Vec<_> = self
+            .inner
+            .load_latencies
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect();
+        if latencies.is_empty() {
+            return None;
+        }
+        latencies.sort_unstable();
+        let p99 = latencies[(latencies.len() * 99).div_ceil(100) - 1];
+        Some(p99 + SUGGESTED_CACHE_TIMEOUT_MARGIN)
+    }
+
+    /// Returns whether the cached credentials carry a session token, if any are cached.
+    ///
+    /// The token itself is never exposed. This never triggers a refresh.
+    pub async fn has_session_token(&self) -> Option<bool> {
+        self.inner
+            .current
+            .load()
+            .as_deref()
+            .map(|c| c.credentials.session_token().is_some())
+    }
+
+    /// Returns the expiry of the cached credentials, if any.
+    ///
+    /// This never triggers a refresh, and the returned time may be in the past.
+    pub async fn cached_expiry(&self) -> Option<SystemTime> {
+        self.inner
+            .current
+            .load()
+            .as_deref()
+            .and_then(|c| c.credentials.expiry())
+    }
+
+    /// Returns whether the cached credentials become stale within `within`.
+    ///
+    /// The credentials are stale once inside `cache_timeout` (and the delivery cushion) of their
+    /// expiry, or inside the reduced margin of credentials with a short lifetime, as for the
+    /// provider itself. An empty cache counts as expiring, as does a `within` too long to be
+    /// represented. This is meant for health
+    /// or readiness probes and never triggers a refresh.
+    pub async fn is_expiring_soon(&self, within: Duration) -> bool {
+        match self.inner.current.load().as_deref() {
+            Some(entry) => self.inner.is_expiring_within(entry, within),
+            None => true,
+        }
+    }
+
+    /// Waits until credentials can be obtained, or until `deadline` has elapsed.
+    ///
+    /// Failed attempts are retried with a short exponential backoff. This is meant for startup
+    /// sequences where the source credentials may not be available right away, e.g. while the
+    /// instance metadata service warms up. On timeout, the error of the last attempt is returned,
+    /// or a timeout error if the first attempt didn't complete in time. A `deadline` too long to be
+    /// represented, such as `Duration::MAX`, never elapses.
+    pub async fn wait_for_credentials(&self, deadline: Duration) -> credentials::Result {
+        let deadline = Instant::now().checked_add(deadline);
+        let remaining =
+            || deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        let mut backoff = INITIAL_WAIT_BACKOFF;
+        let mut last_error = None;
+        loop {
+            let attempt = match remaining() {
+                Some(remaining) => {
+                    Timeout::new(self.get_credentials(), self.inner.sleep.sleep(remaining))
+                        .await
+                        .map_err(|_| remaining)
+                }
+                None => Ok(self.get_credentials().await),
+            };
+            match attempt {
+                Ok(Ok(creds)) => return Ok(creds),
+                Ok(Err(e)) => {
+                    debug!(
+                        "Failed to get credentials, retrying in {:?}: {}",
+                        backoff, e
+                    );
+                    last_error = Some(e);
+                }
+                Err(remaining) => {
+                    return Err(last_error
+                        .unwrap_or_else(|| CredentialsError::provider_timed_out(remaining)))
+                }
             }
+            let wait = match remaining() {
+                Some(remaining) if remaining.is_zero() => return Err(last_error.unwrap()),
+                Some(remaining) => backoff.min(remaining),
+                None => backoff,
+            };
+            self.inner.sleep.sleep(wait).await;
+            backoff = (backoff * 2).min(MAX_WAIT_BACKOFF);
         }
-        None
     }
 
-    async fn load_credentials(&self) -> aws_types::credentials::Result {
-        let sts_config = aws_config::load_from_env().await;
-        let sts_client = aws_sdk_sts::client::Client::new(&sts_config);
-        sts_client
-            .assume_role()
-            .role_arn(&self.role_arn)
-            .set_role_session_name(self.session_name.clone())
-            .set_external_id(self.external_id.clone())
-            .set_source_identity(self.source_identity.clone())
-            .set_duration_seconds(self.session_duration)
-            .send()
+    /// Stores the given credentials in the cache, replacing any cached ones.
+    ///
+    /// They are returned by the provider without calling STS until they become stale, as if they
+    /// had been obtained from STS. This is meant for tests of downstream code
+    /// and for bootstrapping with credentials obtained through another channel. The credentials
+    /// must have an expiry.
+    #[cfg(any(test, feature = "test-util"))]
+    pub async fn set_cached_credentials(&self, creds: Credentials) -> Result<(), CredentialsError> {
+        if creds.expiry().is_none() {
+            return Err(CredentialsError::invalid_configuration(
+                "credentials stored in the cache must have an expiry",
+            ));
+        }
+        let entry = CachedCredentials {
+            credentials: creds,
+            assumed_role_arn: None,
+            reduced_margin: None,
+            hold_until: None,
+            fetched_at: self.inner.time.now(),
+        };
+        let mut lock = self.inner.cred_cache.write().await;
+        self.inner.store(&mut lock, Some(entry));
+        Ok(())
+    }
+
+    /// Returns the credentials along with the metadata of the `AssumeRole` call they come from.
+    ///
+    /// The credentials are obtained as by the provider itself, refreshing them if needed. Unlike
+    /// separate calls to the accessors such as [`cached_expiry`](Self::cached_expiry), the returned
+    /// fields always describe the same credentials, even if a refresh happens concurrently.
+    pub async fn get_credentials_with_metadata(
+        &self,
+    ) -> Result<AssumedCredentials, CredentialsError> {
+        let entry = self.get_entry().await?;
+        Ok(AssumedCredentials {
+            expiry: entry.credentials.expiry().unwrap(),
+            credentials: entry.credentials,
+            assumed_role_arn: entry.assumed_role_arn,
+            session_name: self.inner.params.session_name.clone(),
+        })
+    }
+
+    /// Returns the credentials as the provider does, giving up at `deadline`.
+    ///
+    /// This is meant for requests with an overall deadline, so that a refresh they trigger doesn't
+    /// outlive them. If the deadline has already passed, fails right away without looking at the
+    /// cache or calling STS. When giving up, a refresh made by this call is abandoned, and the next
+    /// read starts over.
+    pub async fn get_credentials_with_deadline(&self, deadline: Instant) -> credentials::Result {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(CredentialsError::provider_timed_out(Duration::ZERO));
+        }
+        Timeout::new(self.get_credentials(), self.inner.sleep.sleep(remaining))
             .await
-            .map_err(CredentialsError::provider_error)?
-            .credentials
-            .map(|c| {
-                credentials::Credentials::new(
-                    c.access_key_id.unwrap(),
-                    c.secret_access_key.unwrap(),
-                    c.session_token,
-                    c.expiration.map(|e| e.try_into().unwrap()),
-                    "STSCredentialsProvider",
-                )
-            })
-            .ok_or_else(|| {
-                CredentialsError::not_loaded("STS Assume Role returned no credentials".to_string())
-            })
+            .unwrap_or_else(|_| Err(CredentialsError::provider_timed_out(remaining)))
+    }
+
+    /// Replaces the cached credentials with new ones, even if they are still fresh.
+    ///
+    /// Returns the previously cached credentials, if any, along with the new ones. This is meant
+    /// for checking that rotation works, e.g. by comparing the access key ids. If the new
+    /// credentials can't be obtained, the cache is left untouched.
+    pub async fn force_rotate(
+        &self,
+    ) -> Result<(Option<Credentials>, Credentials), CredentialsError> {
+        let mut lock = self.inner.cred_cache.write().await;
+        let entry = self.inner.load_credentials().await?;
+        let new = entry.credentials.clone();
+        let old = lock.take().map(|old| old.credentials);
+        self.inner.store(&mut lock, Some(entry));
+        Ok((old, new))
+    }
+
+    /// Checks that the cached credentials remain valid for at least `duration`.
+    ///
+    /// This is meant for tests, which can compare an error message rather than `SystemTime`
+    /// values. The error describes why the check failed. It never triggers a refresh.
+    #[cfg(any(test, feature = "test-util"))]
+    pub async fn assert_valid_for(&self, duration: Duration) -> Result<(), String> {
+        let expiry = self
+            .cached_expiry()
+            .await
+            .ok_or_else(|| "no credentials are cached".to_string())?;
+        match expiry.duration_since(self.inner.time.now()) {
+            Ok(remaining) if remaining >= duration => Ok(()),
+            Ok(remaining) => Err(format!(
+                "the cached credentials expire in {:?}, less than the required {:?}",
+                remaining, duration
+            )),
+            Err(e) => Err(format!(
+                "the cached credentials expired {:?} ago",
+                e.duration()
+            )),
+        }
+    }
+
+    /// Refreshes the credentials if the cached ones have the given access key id.
+    ///
+    /// This is meant for reacting to a key being rejected, e.g. after it was revoked: if another
+    /// task already replaced it, nothing is done. The check and the refresh happen under the lock
+    /// of the cache, so that concurrent calls for the same key refresh only once. Returns whether
+    /// the credentials were refreshed. If the refresh fails, the rejected credentials are dropped
+    /// from the cache.
+    pub async fn refresh_if_matches(&self, access_key_id: &str) -> Result<bool, CredentialsError> {
+        let mut lock = self.inner.cred_cache.write().await;
+        match lock.as_ref() {
+            Some(entry) if entry.credentials.access_key_id() == access_key_id => {}
+            _ => return Ok(false),
+        }
+        match self.inner.load_credentials().await {
+            Ok(entry) => {
+                self.inner.store(&mut lock, Some(entry));
+                Ok(true)
+            }
+            Err(e) => {
+                self.inner.store(&mut lock, None);
+                Err(e)
+            }
+        }
     }
 
     /// Returns the credentials from cache or updates the cache if they're expired
+    ///
+    /// In passthrough mode, returns the source credentials instead.
     async fn get_credentials(&self) -> aws_types::credentials::Result {
-        match self.stored_credentials().await {
-            Some(creds) => {
+        if self.inner.passthrough {
+            return self.inner.client.source_credentials().await;
+        }
+        self.get_entry().await.map(|entry| entry.credentials)
+    }
+
+    /// Returns the cache entry, updating the cache if the credentials are expired
+    async fn get_entry(&self) -> Result<CachedCredentials, CredentialsError> {
+        match self.inner.stored_credentials() {
+            Lookup::Fresh(entry) => {
                 debug!("Returning cached credentials");
-                Ok(creds)
+                Ok(entry)
             }
-            None => {
+            Lookup::RefreshAhead(entry) => {
+                debug!("Returning cached credentials and refreshing them in the background");
+                Inner::refresh_in_background(&self.inner);
+                Ok(entry)
+            }
+            Lookup::Stale(stale) => {
                 debug!("No valid credentials in cache. Getting from STS");
-                let mut lock = self.cred_cache.write().await;
-                let new_creds = self.load_credentials().await;
-                match &new_creds {
-                    Ok(creds) => *lock = Some(creds.clone()),
-                    Err(_) => *lock = None,
+                let mut lock = match (self.inner.in_flight, self.inner.lock_timeout) {
+                    (InFlightPolicy::FailFast, _) => match self.inner.cred_cache.try_write() {
+                        Ok(lock) => lock,
+                        Err(_) => return refresh_in_flight(stale, self.inner.time.now()),
+                    },
+                    (InFlightPolicy::Wait, None) => self.inner.cred_cache.write().await,
+                    (InFlightPolicy::Wait, Some(timeout)) => {
+                        let lock = self.inner.cred_cache.write();
+                        match Timeout::new(lock, self.inner.sleep.sleep(timeout)).await {
+                            Ok(lock) => lock,
+                            Err(_) => return lock_timed_out(stale, self.inner.time.now(), timeout),
+                        }
+                    }
                 };
-                new_creds
+                // Another task may have refreshed the credentials while we were waiting for the
+                // lock
+                if let Some(entry) = lock.as_ref() {
+                    if let Lookup::Fresh(entry) | Lookup::RefreshAhead(entry) =
+                        self.inner.lookup(entry)
+                    {
+                        return Ok(entry);
+                    }
+                }
+                if let Err(e) = self.inner.check_call_cap() {
+                    return match stale {
+                        Some(entry)
+                            if entry.credentials.expiry().unwrap() > self.inner.time.now() =>
+                        {
+                            warn!("{}, returning the stale credentials", e);
+                            Ok(entry)
+                        }
+                        _ => Err(e),
+                    };
+                }
+                match self.inner.load_credentials().await {
+                    Ok(entry) => {
+                        self.inner.store(&mut lock, Some(entry.clone()));
+                        Ok(entry)
+                    }
+                    Err(e) => {
+                        if self.inner.clear_cache_on_error {
+                            self.inner.store(&mut lock, None);
+                        }
+                        Err(e)
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Result of looking up the credentials in the cache.
+enum Lookup {
+    /// The cached credentials can be used as-is.
+    Fresh(CachedCredentials),
+    /// The cached credentials can be used, but should be refreshed.
+    RefreshAhead(CachedCredentials),
+    /// There are no usable credentials in the cache.
+    ///
+    /// Holds the cached credentials, if any.
+    Stale(Option<CachedCredentials>),
+}
+
+/// Margins applied when deciding whether cached credentials can be used.
+#[derive(Clone, Copy, Debug)]
+struct Margins {
+    cache_timeout: Duration,
+    refresh_ahead: Duration,
+    delivery_cushion: Duration,
+}
+
+/// How usable credentials are, irrespective of the parameters they were obtained with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Freshness {
+    Fresh,
+    RefreshAhead,
+    Stale,
+}
+
+/// Decides how usable credentials expiring at `expiry` are at `now`.
+///
+/// This only depends on its arguments, so that it can be reasoned about independently of the cache
+/// and the clock. It upholds the following invariants:
+/// * credentials expiring at or before `now + delivery_cushion + cache_timeout` are `Stale`, so
+///   credentials within `cache_timeout` of their expiry are never returned from the cache;
+/// * credentials are only `Fresh` if they expire after `now + delivery_cushion + refresh_ahead`;
+/// * it never panics: if adding the margins overflows `SystemTime`, no expiry can be late enough
+///   and the credentials are `Stale`.
+///
+/// An empty cache never reaches this function, and is always a miss.
+fn freshness(expiry: SystemTime, now: SystemTime, margins: Margins) -> Freshness {
+    // The time at which the credentials will actually be used
+    let Some(use_time) = now.checked_add(margins.delivery_cushion) else {
+        return Freshness::Stale;
+    };
+    let beyond = |margin| use_time.checked_add(margin).is_some_and(|t| expiry > t);
+    if !beyond(margins.cache_timeout) {
+        Freshness::Stale
+    } else if beyond(margins.refresh_ahead) {
+        Freshness::Fresh
+    } else {
+        Freshness::RefreshAhead
+    }
+}
+
+impl Inner {
+    /// Looks up the stored credentials
+    /// The credentials are valid iff
+    /// * they're not None
+    /// * they expire at least `cache_timeout` in the future
+    ///
+    /// Valid credentials expiring less than `refresh_ahead` in the future should be refreshed.
+    /// Both thresholds are extended by `delivery_cushion`. Credentials that had already expired
+    /// when they were obtained are still returned for a few seconds, so that a skewed clock
+    /// doesn't make each read call STS.
+    ///
+    /// It reads the lock-free copy of the cache, so that it can be called from synchronous code
+    /// and doesn't wait for a refresh holding the lock, e.g. one started by
+    /// [`force_rotate`](STSCredentialsProvider::force_rotate). Only the reads finding stale
+    /// credentials go through the lock of the cache.
+    fn stored_credentials(&self) -> Lookup {
+        match self.current.load().as_deref() {
+            Some(c) => self.lookup(c),
+            None => Lookup::Stale(None),
+        }
+    }
+
+    /// Replaces the content of the cache, keeping its lock-free copy in sync.
+    ///
+    /// `cache` must be the content of `cred_cache`, borrowed through its write lock.
+    fn store(&self, cache: &mut Option<CachedCredentials>, entry: Option<CachedCredentials>) {
+        self.current.store(entry.clone().map(Arc::new));
+        *cache = entry;
+    }
+
+    /// Checks whether the given cache entry can be used.
+    fn lookup(&self, entry: &CachedCredentials) -> Lookup {
+        if let Some(hold_until) = entry.hold_until {
+            if self.time.now() < hold_until {
+                return Lookup::Fresh(entry.clone());
+            }
+        }
+        let expiration = entry.credentials.expiry().unwrap();
+        match freshness(expiration, self.time.now(), self.margins(entry)) {
+            Freshness::Fresh => Lookup::Fresh(entry.clone()),
+            Freshness::RefreshAhead => Lookup::RefreshAhead(entry.clone()),
+            Freshness::Stale => Lookup::Stale(Some(entry.clone())),
+        }
+    }
+
+    /// Returns whether the given cache entry becomes stale within `within`.
+    ///
+    /// Entries held after having expired on arrival count as stale.
+    fn is_expiring_within(&self, entry: &CachedCredentials, within: Duration) -> bool {
+        let mut margins = self.margins(entry);
+        margins.cache_timeout = match margins.cache_timeout.checked_add(within) {
+            Some(cache_timeout) => cache_timeout,
+            None => return true,
+        };
+        let expiration = entry.credentials.expiry().unwrap();
+        freshness(expiration, self.time.now(), margins) == Freshness::Stale
+    }
+
+    /// Returns the margins the given cache entry is checked against.
+    fn margins(&self, entry: &CachedCredentials) -> Margins {
+        match entry.reduced_margin {
+            // Credentials whose lifetime was too short for the configured margins are only checked
+            // against the reduced margin, and never refreshed ahead.
+            Some(margin) => Margins {
+                cache_timeout: margin,
+                refresh_ahead: margin,
+                delivery_cushion: Duration::ZERO,
+            },
+            None => Margins {
+                cache_timeout: self.cache_timeout,
+                refresh_ahead: self.refresh_ahead,
+                delivery_cushion: self.delivery_cushion,
+            },
+        }
+    }
+
+    /// Assumes the first role that can be assumed, starting with the one that worked last time.
+    ///
+    /// If none can, returns the error of the primary role. Otherwise, returns the parameters of the
+    /// role that was assumed along with the output of the call.
+    async fn assume_any_role(
+        &self,
+    ) -> Result<(AssumeRoleOutput, &AssumeRoleParams), CredentialsError> {
+        let preferred = self.preferred_role.load(Ordering::Relaxed);
+        let order =
+            iter::once(preferred).chain((0..=self.fallbacks.len()).filter(|&i| i != preferred));
+        let mut primary_error = None;
+        for index in order {
+            let params = match index {
+                0 => &self.params,
+                _ => &self.fallbacks[index - 1],
+            };
+            match self.assume_role(params).await {
+                Ok(output) => {
+                    if index != preferred {
+                        info!(
+                            "Assumed role {}, which will be tried first from now on",
+                            params.role_arn
+                        );
+                        self.preferred_role.store(index, Ordering::Relaxed);
+                    }
+                    return Ok((output, params));
+                }
+                Err(e) if self.fallbacks.is_empty() => return Err(e),
+                Err(e) => {
+                    warn!("Failed to assume role {}: {}", params.role_arn, e);
+                    if index == 0 {
+                        primary_error = Some(e);
+                    }
+                }
+            }
+        }
+        Err(primary_error.unwrap())
+    }
+
+    /// Sends the `AssumeRole` call.
+    ///
+    /// Each call is counted against the cap on the number of calls before being sent. With an
+    /// identity token provider, sends the `AssumeRoleWithWebIdentity` call instead.
+    async fn assume_role(
+        &self,
+        params: &AssumeRoleParams,
+    ) -> Result<AssumeRoleOutput, CredentialsError> {
+        if let Some(tokens) = &self.identity_tokens {
+            return self
+                .assume_role_with_web_identity(tokens.as_ref(), params)
+                .await;
+        }
+        let client = self.client.get().await?;
+        self.reserve_call()?;
+        let result = self.send_assume_role(&client, params).await;
+        let result = match result {
+            Err(SdkError::ServiceError { ref err, .. }) if is_expired_source(err) => {
+                // Only retried once: if fresh source credentials are expired too, retrying again
+                // won't help
+                warn!("The source credentials have expired. Reloading them and retrying");
+                self.client.reset().await;
+                let client = self.client.get().await?;
+                self.reserve_call()?;
+                self.send_assume_role(&client, params).await
+            }
+            result => result,
+        };
+        match result {
+            Ok(output) => Ok(output),
+            Err(e) => Err(self.assume_role_error(e, params).await),
+        }
+    }
+
+    /// Turns an error returned by the `AssumeRole` call into the one returned by the provider.
+    ///
+    /// The error is given the region and endpoint STS was called with, as a misrouted call is
+    /// otherwise hard to tell from an error of STS itself.
+    async fn assume_role_error(
+        &self,
+        e: SdkError<AssumeRoleError>,
+        params: &AssumeRoleParams,
+    ) -> CredentialsError {
+        let region_disabled = matches!(
+            &e,
+            SdkError::ServiceError { err, .. } if err.is_region_disabled_exception()
+        );
+        let denied_hint = match &e {
+            SdkError::ServiceError { err, .. } if err.code() == Some("AccessDenied") => Some(
+                access_denied_hint(err.message().unwrap_or_default(), params),
+            ),
+            _ => None,
+        };
+        // The external id is a shared secret, make sure it doesn't end up in logs
+        let e = error::redact(e, params.external_id.as_deref());
+        let target = self.client.target().await;
+        let message = match (region_disabled, denied_hint) {
+            (true, _) => format!(
+                "STS is disabled in the region for the account of the source credentials ({}). \
+                 Enable the region in the account settings, or call STS in another region",
+                target
+            ),
+            (false, Some(hint)) => format!("AssumeRole was denied ({}). {}", target, hint),
+            (false, None) => format!("AssumeRole failed ({})", target),
+        };
+        CredentialsError::provider_error(ContextError::new(message, e))
+    }
+
+    /// Sends the `AssumeRoleWithWebIdentity` call, with a token from `tokens`.
+    ///
+    /// The output is converted into that of `AssumeRole`, so that the rest of the load doesn't
+    /// depend on the call made.
+    async fn assume_role_with_web_identity(
+        &self,
+        tokens: &dyn IdentityTokenProvider,
+        params: &AssumeRoleParams,
+    ) -> Result<AssumeRoleOutput, CredentialsError> {
+        let token = tokens.token().await?;
+        let client = self.client.get().await?;
+        self.reserve_call()?;
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire(self.sleep.as_ref()).await;
+        }
+        let started = Instant::now();
+        let output = client
+            .assume_role_with_web_identity()
+            .role_arn(&params.role_arn)
+            .set_role_session_name(self.session_name(params))
+            .web_identity_token(token.as_str())
+            .set_duration_seconds(params.session_duration)
+            .set_policy(non_empty(&params.policy))
+            .send()
+            .await;
+        match output {
+            Ok(output) => {
+                self.record_latency(started.elapsed());
+                Ok(AssumeRoleOutput::builder()
+                    .set_credentials(output.credentials)
+                    .set_assumed_role_user(output.assumed_role_user)
+                    .set_packed_policy_size(output.packed_policy_size)
+                    .set_source_identity(output.source_identity)
+                    .build())
+            }
+            Err(e) => {
+                let message = format!(
+                    "AssumeRoleWithWebIdentity failed ({})",
+                    self.client.target().await
+                );
+                Err(CredentialsError::provider_error(ContextError::new(
+                    message, e,
+                )))
+            }
+        }
+    }
+
+    async fn send_assume_role(
+        &self,
+        client: &Client,
+        params: &AssumeRoleParams,
+    ) -> Result<AssumeRoleOutput, SdkError<AssumeRoleError>> {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            rate_limiter.acquire(self.sleep.as_ref()).await;
+        }
+        let started = Instant::now();
+        let output = client
+            .assume_role()
+            .role_arn(&params.role_arn)
+            .set_role_session_name(self.session_name(params))
+            .set_external_id(params.sts_external_id())
+            .set_source_identity(params.sts_source_identity())
+            .set_duration_seconds(params.session_duration)
+            .set_tags(params.sts_tags())
+            .set_policy(non_empty(&params.policy))
+            .send()
+            .await;
+        if output.is_ok() {
+            self.record_latency(started.elapsed());
+        }
+        output
+    }
+
+    /// Returns an error if the provider has made the maximum number of `AssumeRole` calls.
+    fn check_call_cap(&self) -> Result<(), CredentialsError> {
+        match self.max_lifetime_calls {
+            Some(max) if self.sts_calls.load(Ordering::Relaxed) >= max => {
+                Err(call_cap_reached(max))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a call about to be sent to STS, failing without counting it if the cap on the number
+    /// of calls has been reached.
+    ///
+    /// Unlike [`check_call_cap`](Self::check_call_cap), this holds for every call of a load,
+    /// including those to fallback roles and retries.
+    fn reserve_call(&self) -> Result<(), CredentialsError> {
+        let max = self.max_lifetime_calls.unwrap_or(u64::MAX);
+        self.sts_calls
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |calls| {
+                (calls < max).then_some(calls + 1)
+            })
+            .map(drop)
+            .map_err(|_| call_cap_reached(max))
+    }
+
+    /// Records the duration of a successful call to STS, dropping the oldest one if needed.
+    fn record_latency(&self, latency: Duration) {
+        let mut latencies = self.load_latencies.lock().unwrap();
+        if latencies.len() == LATENCY_SAMPLES {
+            latencies.pop_front();
+        }
+        latencies.push_back(latency);
+    }
+
+    /// Returns the role session name to send, with a unique suffix if enabled.
+    fn session_name(&self, params: &AssumeRoleParams) -> Option<String> {
+        let base = params
+            .session_name
+            .as_deref()
+            .filter(|name| !name.is_empty())?;
+        if !self.unique_session_names {
+            return Some(base.to_string());
+        }
+        let mut hasher = RandomState::new().build_hasher();
+        self.session_counter
+            .fetch_add(1, Ordering::Relaxed)
+            .hash(&mut hasher);
+        SystemTime::now().hash(&mut hasher);
+        let suffix = format!("{:016x}", hasher.finish());
+        let base: String = base
+            .chars()
+            .take(MAX_SESSION_NAME_LENGTH - SESSION_NAME_SUFFIX_LENGTH - 1)
+            .collect();
+        Some(format!(
+            "{}-{}",
+            base,
+            &suffix[..SESSION_NAME_SUFFIX_LENGTH]
+        ))
+    }
+
+    /// Loads the credentials, checking when they were issued and how long they are valid.
+    async fn load_credentials(&self) -> Result<CachedCredentials, CredentialsError> {
+        let (entry, requested_duration) = self.load_entry().await?;
+        let mut entry = self.check_issue_time(entry, requested_duration).await?;
+        if let Lookup::Stale(_) = self.lookup(&entry) {
+            let lifetime = entry
+                .credentials
+                .expiry()
+                .unwrap()
+                .duration_since(self.time.now())
+                .unwrap_or_default();
+            if lifetime.is_zero() {
+                // Return them anyway, as refreshing again right away would most likely give the
+                // same result. They are kept for a while, so that each read doesn't call STS.
+                let count = self.stale_loads.fetch_add(1, Ordering::Relaxed) + 1;
+                warn!(
+                    "Credentials just obtained from STS have already expired ({} times so far). \
+                     Check the system clock. They will be returned for {}s before calling STS \
+                     again",
+                    count,
+                    EXPIRED_LOAD_HOLD.as_secs()
+                );
+                entry.hold_until = self.time.now().checked_add(EXPIRED_LOAD_HOLD);
+                return Ok(entry);
+            }
+            match self.short_lifetime {
+                ShortLifetimePolicy::Serve => {
+                    if !self.short_lifetime_logged.swap(true, Ordering::Relaxed) {
+                        warn!(
+                            "Credentials obtained from STS are only valid for {}s, which doesn't \
+                             leave room for cache_timeout ({}s). They will be refreshed halfway \
+                             through their lifetime. Check that cache_timeout is shorter than the \
+                             session duration",
+                            lifetime.as_secs(),
+                            self.cache_timeout.as_secs()
+                        );
+                    }
+                    entry.reduced_margin = Some(lifetime / 2);
+                }
+                ShortLifetimePolicy::Fail => {
+                    return Err(CredentialsError::invalid_configuration(format!(
+                        "credentials obtained from STS are only valid for {}s, which doesn't leave \
+                         room for cache_timeout ({}s) and delivery_cushion ({}s). The session \
+                         duration must be longer than both",
+                        lifetime.as_secs(),
+                        self.cache_timeout.as_secs(),
+                        self.delivery_cushion.as_secs()
+                    )))
+                }
+            }
+        }
+        Ok(entry)
+    }
+
+    /// Fetches a cache entry, within a span if the `otel` feature is enabled.
+    ///
+    /// Fails without calling STS once the cap on the number of calls has been reached. Every fetch
+    /// goes through here, including the one made again by [`ClockSkewPolicy::Refresh`].
+    async fn load_entry(&self) -> Result<(CachedCredentials, Duration), CredentialsError> {
+        self.check_call_cap()?;
+        #[cfg(feature = "otel")]
+        return otel::traced_load(self).await;
+        #[cfg(not(feature = "otel"))]
+        self.fetch_entry().await
+    }
+
+    /// Assumes the role and builds the cache entry from the output of the call.
+    ///
+    /// Also returns the session duration that was requested, or the default one of STS.
+    async fn fetch_entry(&self) -> Result<(CachedCredentials, Duration), CredentialsError> {
+        let fetched_at = self.time.now();
+        let (output, params) = self.assume_any_role().await?;
+        let requested = params
+            .session_duration
+            .and_then(|d| u64::try_from(d).ok())
+            .map(Duration::from_secs);
+        let c = output.credentials.as_ref().ok_or_else(|| {
+            CredentialsError::not_loaded("STS Assume Role returned no credentials".to_string())
+        })?;
+        let session_token = match self.session_token {
+            SessionTokenPolicy::Passthrough => c.session_token.clone(),
+            SessionTokenPolicy::Require => Some(c.session_token.clone().ok_or_else(|| {
+                CredentialsError::provider_error(
+                    "STS returned credentials without a session token, which is required",
+                )
+            })?),
+            SessionTokenPolicy::Omit => None,
+        };
+        let credentials = credentials::Credentials::new(
+            c.access_key_id.clone().unwrap(),
+            c.secret_access_key.clone().unwrap(),
+            session_token,
+            c.expiration.map(|e| e.try_into().unwrap()),
+            self.provider_name,
+        );
+        let credentials = match &self.post_load {
+            Some(PostLoad(hook)) => {
+                let credentials = hook(credentials, &output)?;
+                if credentials.expiry().is_none() {
+                    return Err(CredentialsError::invalid_configuration(
+                        "the post_load hook returned credentials without an expiry",
+                    ));
+                }
+                credentials
             }
+            None => credentials,
+        };
+        let entry = CachedCredentials {
+            credentials,
+            assumed_role_arn: output.assumed_role_user.and_then(|u| u.arn),
+            reduced_margin: None,
+            hold_until: None,
+            fetched_at,
+        };
+        if let Some(requested) = requested {
+            self.check_granted_lifetime(&entry, requested);
+        }
+        Ok((entry, requested.unwrap_or(DEFAULT_SESSION_DURATION)))
+    }
+
+    /// Handles credentials issued in the future, according to the clock skew policy.
+    ///
+    /// STS doesn't return the issue time, so it is inferred from the expiry and the requested
+    /// session duration. As STS may grant a shorter session than requested, the inferred time is a
+    /// lower bound. If it is in the future, the local clock is behind that of STS. This is the
+    /// counterpart of the check for credentials that are already expired when obtained, which
+    /// catches clocks that are ahead.
+    async fn check_issue_time(
+        &self,
+        entry: CachedCredentials,
+        requested_duration: Duration,
+    ) -> Result<CachedCredentials, CredentialsError> {
+        let ahead = match self.issued_ahead(&entry.credentials, requested_duration) {
+            Some(ahead) => ahead,
+            None => return Ok(entry),
+        };
+        match self.clock_skew {
+            ClockSkewPolicy::Wait if ahead <= MAX_NOT_BEFORE_WAIT => {
+                debug!(
+                    "Credentials were issued {:?} in the future, waiting for them to be valid",
+                    ahead
+                );
+                self.sleep.sleep(ahead).await;
+                Ok(entry)
+            }
+            ClockSkewPolicy::Refresh => {
+                debug!(
+                    "Credentials were issued {:?} in the future, requesting new ones",
+                    ahead
+                );
+                let (entry, requested_duration) = self.load_entry().await?;
+                if let Some(ahead) = self.issued_ahead(&entry.credentials, requested_duration) {
+                    warn_issued_ahead(ahead);
+                }
+                Ok(entry)
+            }
+            ClockSkewPolicy::Wait | ClockSkewPolicy::Serve => {
+                warn_issued_ahead(ahead);
+                Ok(entry)
+            }
+        }
+    }
+
+    /// Returns how far in the future of the local clock the credentials were issued, if they were.
+    fn issued_ahead(
+        &self,
+        credentials: &Credentials,
+        requested_duration: Duration,
+    ) -> Option<Duration> {
+        let issued_at = credentials.expiry()?.checked_sub(requested_duration)?;
+        issued_at
+            .duration_since(self.time.now())
+            .ok()
+            .filter(|ahead| !ahead.is_zero())
+    }
+
+    /// Warns once if STS granted a session materially shorter than requested.
+    ///
+    /// This typically happens with role chaining, which caps sessions to one hour.
+    fn check_granted_lifetime(&self, entry: &CachedCredentials, requested: Duration) {
+        let granted = entry.granted_lifetime();
+        if granted + GRANTED_DURATION_TOLERANCE < requested
+            && !self.short_grant_logged.swap(true, Ordering::Relaxed)
+        {
+            warn!(
+                "STS granted a session of about {}s for role {}, shorter than the {}s requested. \
+                 Sessions obtained through role chaining are limited to one hour",
+                granted.as_secs(),
+                entry
+                    .assumed_role_arn
+                    .as_deref()
+                    .unwrap_or(&self.params.role_arn),
+                requested.as_secs()
+            );
         }
     }
+
+    /// Refreshes the cached credentials in a background task.
+    ///
+    /// The refresh holds the lock of the cache, so that reads finding the credentials stale in the
+    /// meantime wait for its result, as for any other refresh, instead of calling STS again. Reads
+    /// of usable credentials don't take the lock, so they aren't blocked. Does nothing if a
+    /// background refresh is already running.
+    fn refresh_in_background(inner: &Arc<Self>) {
+        // The builder rejects `refresh_ahead` without a spawner
+        let Some(spawner) = inner.spawner.as_ref() else {
+            return;
+        };
+        if inner.refreshing.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let inner = inner.clone();
+        // Keep the span of the read that triggered the refresh
+        let span = tracing::Span::current();
+        spawner.spawn(Box::pin(
+            async move {
+                let mut lock = inner.cred_cache.write().await;
+                // A read may have refreshed the credentials while we were waiting for the lock
+                let refreshed = matches!(
+                    lock.as_ref().map(|entry| inner.lookup(entry)),
+                    Some(Lookup::Fresh(_))
+                );
+                if !refreshed {
+                    match inner.load_credentials().await {
+                        Ok(entry) => inner.store(&mut lock, Some(entry)),
+                        // The cached credentials are still valid, the next read will retry
+                        Err(e) => warn!("Background refresh of the credentials failed: {}", e),
+                    }
+                }
+                drop(lock);
+                inner.refreshing.store(false, Ordering::Release);
+            }
+            .instrument(span),
+        ));
+    }
+}
+
+/// Returns the error of a load prevented by the cap on the number of calls.
+fn call_cap_reached(max: u64) -> CredentialsError {
+    CredentialsError::provider_error(format!(
+        "the provider has made its maximum of {} AssumeRole calls",
+        max
+    ))
+}
+
+/// Returns what to do when the lock of the cache couldn't be acquired within `timeout`.
+///
+/// The stale credentials are returned if they haven't expired yet.
+fn lock_timed_out(
+    stale: Option<CachedCredentials>,
+    now: SystemTime,
+    timeout: Duration,
+) -> Result<CachedCredentials, CredentialsError> {
+    match stale {
+        Some(entry) if entry.credentials.expiry().unwrap() > now => {
+            warn!(
+                "Timed out after {:?} waiting for a refresh in progress, returning the stale \
+                 credentials",
+                timeout
+            );
+            Ok(entry)
+        }
+        _ => Err(CredentialsError::provider_error(format!(
+            "timed out after {:?} waiting for a refresh of the credentials in progress",
+            timeout
+        ))),
+    }
+}
+
+/// Returns the stale credentials when another task is refreshing them, if they haven't expired.
+fn refresh_in_flight(
+    stale: Option<CachedCredentials>,
+    now: SystemTime,
+) -> Result<CachedCredentials, CredentialsError> {
+    match stale {
+        Some(entry) if entry.credentials.expiry().unwrap() > now => {
+            debug!("A refresh is in progress, returning the stale credentials");
+            Ok(entry)
+        }
+        _ => Err(CredentialsError::provider_error(
+            "the credentials are expired and a refresh is in progress",
+        )),
+    }
+}
+
+/// Reports credentials issued in the future, which are returned anyway.
+fn warn_issued_ahead(ahead: Duration) {
+    warn!(
+        "Credentials were issued {}s in the future, the system clock is probably behind. \
+         Returning them anyway",
+        ahead.as_secs()
+    );
+}
+
+/// Suggests the likely cause of an `AccessDenied` error returned by `AssumeRole`.
+///
+/// STS doesn't say which condition of the trust policy failed, but its message names the missing
+/// permission for the session tags and the source identity, and the usual remaining cause depends
+/// on whether an external id was sent.
+fn access_denied_hint(message: &str, params: &AssumeRoleParams) -> &'static str {
+    if message.contains("sts:TagSession") {
+        "Setting session tags requires the trust policy of the role to allow sts:TagSession"
+    } else if message.contains("sts:SetSourceIdentity") {
+        "Setting a source identity requires the trust policy of the role to allow \
+         sts:SetSourceIdentity"
+    } else if message.contains("MultiFactorAuth") || message.contains("MFA") {
+        "The trust policy of the role seems to require MFA, which the provider can't supply"
+    } else if params.sts_external_id().is_none() {
+        "If the trust policy of the role has an sts:ExternalId condition, set the external id; \
+         otherwise check that it trusts the source credentials"
+    } else {
+        "Check that the external id matches the sts:ExternalId condition of the trust policy of \
+         the role, and that it trusts the source credentials"
+    }
+}
+
+/// Returns the value if it is set and not empty.
+fn non_empty(value: &Option<String>) -> Option<String> {
+    value.clone().filter(|value| !value.is_empty())
+}
+
+/// Returns whether the error means that the credentials used to call STS have expired.
+fn is_expired_source(err: &AssumeRoleError) -> bool {
+    err.code() == Some("ExpiredToken") || err.is_expired_token_exception()
+}
+
+/// Hides the value of a secret option, while still showing whether it is set.
+fn redacted(secret: &Option<String>) -> Option<&'static str> {
+    secret.as_ref().map(|_| REDACTED)
+}
+
+/// Extracts the account id from an ARN such as
+/// `arn:aws:sts::123456789012:assumed-role/role-name/session-name`.
+fn account_id_from_arn(arn: &str) -> Option<&str> {
+    match arn.split(':').nth(4) {
+        Some(account_id) if !account_id.is_empty() => Some(account_id),
+        _ => None,
+    }
 }
 
 impl credentials::ProvideCredentials for STSCredentialsProvider {
@@ -118,3 +1725,9 @@ impl credentials::ProvideCredentials for STSCredentialsProvider {
         aws_types::credentials::future::ProvideCredentials::new(self.get_credentials())
     }
 }
+
+impl From<STSCredentialsProvider> for SharedCredentialsProvider {
+    fn from(provider: STSCredentialsProvider) -> Self {
+        provider.into_shared()
+    }
+}
@@ -0,0 +1,94 @@
+use crate::{CachedCredentials, Inner};
+use aws_types::credentials::CredentialsError;
+use std::time::{Duration, Instant};
+use tracing::field::Empty;
+use tracing::Instrument;
+
+/// Fetches a cache entry within a span describing the `AssumeRole` call.
+///
+/// The span follows the OpenTelemetry conventions understood by `tracing-opentelemetry`, and is a
+/// child of the current span, i.e. of the request that triggered the load. Its attributes are:
+///
+/// * `otel.kind`, `rpc.system`, `rpc.service` and `rpc.method`, describing the call;
+/// * `aws.sts.role_arn`: the ARN of the primary role;
+/// * `aws.sts.assumed_role_arn`: the ARN of the session obtained, on success;
+/// * `aws.sts.granted_duration_s`: the lifetime of the credentials obtained, on success;
+/// * `aws.sts.latency_ms`: the duration of the load;
+/// * `otel.status_code` and `otel.status_message`: the outcome, and the error on failure.
+pub(crate) async fn traced_load(
+    inner: &Inner,
+) -> Result<(CachedCredentials, Duration), CredentialsError> {
+    let span = tracing::info_span!(
+        "sts.assume_role",
+        otel.kind = "client",
+        rpc.system = "aws-api",
+        rpc.service = "STS",
+        rpc.method = match inner.identity_tokens {
+            Some(_) => "AssumeRoleWithWebIdentity",
+            None => "AssumeRole",
+        },
+        aws.sts.role_arn = %inner.params.role_arn,
+        aws.sts.assumed_role_arn = Empty,
+        aws.sts.granted_duration_s = Empty,
+        aws.sts.latency_ms = Empty,
+        otel.status_code = Empty,
+        otel.status_message = Empty,
+    );
+    let started = Instant::now();
+    let result = inner.fetch_entry().instrument(span.clone()).await;
+    span.record("aws.sts.latency_ms", started.elapsed().as_millis() as u64);
+    match &result {
+        Ok((entry, _)) => {
+            if let Some(arn) = &entry.assumed_role_arn {
+                span.record("aws.sts.assumed_role_arn", arn.as_str());
+            }
+            span.record(
+                "aws.sts.granted_duration_s",
+                entry.granted_lifetime().as_secs(),
+            );
+            span.record("otel.status_code", "OK");
+        }
+        Err(e) => {
+            span.record("otel.status_code", "ERROR");
+            span.record("otel.status_message", e.to_string().as_str());
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{self, Reply, StubSts};
+
+    #[tokio::test]
+    async fn loads_are_traced_with_their_outcome() {
+        let (logs, _guard) = testing::capture_logs();
+        let sts = StubSts::new();
+        let provider = sts.provider();
+        provider.get_credentials().await.unwrap();
+        let spans = logs.matching("caching_provider::otel: close");
+        assert_eq!(spans.len(), 1, "{:?}", spans);
+        let span = &spans[0];
+        for field in [
+            "otel.kind=\"client\"",
+            "rpc.method=\"AssumeRole\"",
+            &format!("aws.sts.role_arn={}", testing::ROLE_ARN),
+            &format!(
+                "aws.sts.assumed_role_arn=\"{}\"",
+                testing::assumed_role_arn()
+            ),
+            "aws.sts.granted_duration_s=",
+            "aws.sts.latency_ms=",
+            "otel.status_code=\"OK\"",
+        ] {
+            assert!(span.contains(field), "{} not in {}", field, span);
+        }
+
+        sts.reply([Reply::invalid("rejected")]);
+        provider.force_rotate().await.unwrap_err();
+        let spans = logs.matching("caching_provider::otel: close");
+        let span = &spans[1];
+        assert!(span.contains("otel.status_code=\"ERROR\""), "{}", span);
+        assert!(span.contains("otel.status_message="), "{}", span);
+    }
+}
@@ -0,0 +1,90 @@
+use aws_smithy_async::rt::sleep::AsyncSleep;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Limits the rate of the `AssumeRole` calls of several providers.
+///
+/// STS limits the rate of `AssumeRole` calls per account, which processes running many providers
+/// can exceed when they refresh at the same time. To stay under a ceiling, create a single limiter
+/// and pass a clone of it to the builder of each provider with
+/// [`rate_limiter`](crate::STSCredentialsProviderBuilder::rate_limiter): clones share their
+/// state.
+///
+/// This is a token bucket: up to `calls_per_second` calls may be made at once, after which calls
+/// are spaced evenly. Calls over the limit wait for their turn, in the order they arrived.
+#[derive(Clone, Debug)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+    calls_per_second: f64,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Number of calls that can be made right away, negative when calls are waiting
+    tokens: f64,
+    /// When `tokens` was last updated
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Returns a limiter allowing `calls_per_second` calls per second.
+    pub fn new(calls_per_second: NonZeroU32) -> Self {
+        let calls_per_second = f64::from(calls_per_second.get());
+        Self {
+            bucket: Arc::new(Mutex::new(Bucket {
+                tokens: calls_per_second,
+                updated: Instant::now(),
+            })),
+            calls_per_second,
+        }
+    }
+
+    /// Waits until a call can be made.
+    pub(crate) async fn acquire(&self, sleep: &dyn AsyncSleep) {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            let refilled = now.duration_since(bucket.updated).as_secs_f64() * self.calls_per_second;
+            bucket.tokens = (bucket.tokens + refilled).min(self.calls_per_second) - 1.0;
+            bucket.updated = now;
+            // The token is taken right away, so that later calls wait behind this one
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.calls_per_second)
+        };
+        sleep.sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::RecordingSleep;
+
+    #[tokio::test]
+    async fn calls_over_the_burst_are_spaced_evenly() {
+        let limiter = RateLimiter::new(NonZeroU32::new(10).unwrap());
+        let sleep = RecordingSleep::default();
+        for _ in 0..10 {
+            limiter.acquire(&sleep).await;
+        }
+        assert!(sleep.durations().is_empty(), "{:?}", sleep.durations());
+
+        // Clones share the bucket
+        let clone = limiter.clone();
+        let (_, _) = tokio::join!(limiter.acquire(&sleep), clone.acquire(&sleep));
+        let durations = sleep.durations();
+        assert_eq!(durations.len(), 2);
+        for (duration, expected) in durations.iter().zip([100, 200]) {
+            let expected = Duration::from_millis(expected);
+            assert!(
+                expected.abs_diff(*duration) < Duration::from_millis(20),
+                "{:?} instead of {:?}",
+                duration,
+                expected
+            );
+        }
+    }
+}
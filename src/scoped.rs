@@ -0,0 +1,272 @@
+use crate::{AssumeRoleParams, Lookup, Overrides, STSCredentialsProvider};
+use aws_types::credentials;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// Providers returned by [`STSCredentialsProvider::get_scoped`], by parameters.
+#[derive(Debug, Default)]
+pub(crate) struct ScopedCache {
+    entries: HashMap<AssumeRoleParams, ScopedEntry>,
+    /// Number of reads so far, ordering the entries by last use
+    reads: u64,
+}
+
+impl ScopedCache {
+    /// Evicts the least recently used entry.
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_read)
+            .map(|(params, _)| params.clone());
+        if let Some(params) = oldest {
+            self.entries.remove(&params);
+        }
+    }
+}
+
+/// A provider returned by [`STSCredentialsProvider::get_scoped`], along with its usage.
+#[derive(Debug)]
+pub(crate) struct ScopedEntry {
+    provider: STSCredentialsProvider,
+    last_used: SystemTime,
+    /// Value of [`ScopedCache::reads`] when the entry was last read
+    last_read: u64,
+    hits: u64,
+    misses: u64,
+}
+
+/// Usage of a scoped entry, as returned by [`STSCredentialsProvider::scoped_stats`].
+///
+/// The external id is part of the key of the entry but, being a secret, isn't shown.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ScopedStats {
+    pub role_arn: String,
+    pub session_name: Option<String>,
+    pub policy: Option<String>,
+    /// Number of reads served from the cache
+    pub hits: u64,
+    /// Number of reads that found no usable credentials and called STS
+    pub misses: u64,
+}
+
+impl STSCredentialsProvider {
+    /// Returns credentials for the given overrides, such as a session policy, from a keyed cache.
+    ///
+    /// This serves multi-tenant gateways, which reuse scoped sessions across requests. The first
+    /// call for a set of parameters creates a provider as with
+    /// [`with_overrides`](Self::with_overrides), and later calls reuse it and its cached
+    /// credentials. The key is made of every parameter sent to STS, so credentials
+    /// are never shared between different scopes.
+    ///
+    /// An entry is evicted once it hasn't been used for
+    /// [`scoped_idle_ttl`](crate::STSCredentialsProviderBuilder::scoped_idle_ttl), and its
+    /// credentials are refreshed as those of the provider when they expire. Idle entries are only
+    /// evicted when read, so call [`prune`](Self::prune) regularly to release the memory used by
+    /// keys that are no longer read. At most
+    /// [`scoped_max_entries`](crate::STSCredentialsProviderBuilder::scoped_max_entries) entries are
+    /// kept: creating one more evicts the least recently used.
+    pub async fn get_scoped(&self, overrides: Overrides) -> credentials::Result {
+        let params = self.overridden_params(overrides);
+        let now = self.inner.time.now();
+        let provider = {
+            let mut scoped = self.inner.scoped.lock().unwrap();
+            if let Some(entry) = scoped.entries.get(&params) {
+                if self.is_idle(entry, now) {
+                    scoped.entries.remove(&params);
+                }
+            }
+            if !scoped.entries.contains_key(&params)
+                && scoped.entries.len() >= self.inner.scoped_max_entries
+            {
+                scoped.evict_least_recently_used();
+            }
+            scoped.reads += 1;
+            let reads = scoped.reads;
+            let entry = scoped
+                .entries
+                .entry(params.clone())
+                .or_insert_with(|| ScopedEntry {
+                    provider: self.with_params(params),
+                    last_used: now,
+                    last_read: reads,
+                    hits: 0,
+                    misses: 0,
+                });
+            entry.last_used = now;
+            entry.last_read = reads;
+            match entry.provider.inner.stored_credentials() {
+                Lookup::Fresh(_) | Lookup::RefreshAhead(_) => entry.hits += 1,
+                Lookup::Stale(_) => entry.misses += 1,
+            }
+            STSCredentialsProvider {
+                inner: entry.provider.inner.clone(),
+            }
+        };
+        provider.get_credentials().await
+    }
+
+    /// Returns the usage of the entries of the scoped cache.
+    pub fn scoped_stats(&self) -> Vec<ScopedStats> {
+        self.inner
+            .scoped
+            .lock()
+            .unwrap()
+            .entries
+            .iter()
+            .map(|(params, entry)| ScopedStats {
+                role_arn: params.role_arn.clone(),
+                session_name: params.session_name.clone(),
+                policy: params.policy.clone(),
+                hits: entry.hits,
+                misses: entry.misses,
+            })
+            .collect()
+    }
+
+    /// Evicts the entries of the scoped cache that are idle or hold no unexpired credentials.
+    ///
+    /// Returns the number of entries evicted.
+    pub fn prune(&self) -> usize {
+        let now = self.inner.time.now();
+        let mut scoped = self.inner.scoped.lock().unwrap();
+        let before = scoped.entries.len();
+        scoped.entries.retain(|_, entry| {
+            let expired = match entry.provider.inner.current.load().as_deref() {
+                Some(cached) => cached.credentials.expiry().unwrap() <= now,
+                None => true,
+            };
+            !expired && !self.is_idle(entry, now)
+        });
+        before - scoped.entries.len()
+    }
+
+    /// Returns the number of entries of the scoped cache.
+    pub fn scoped_entry_count(&self) -> usize {
+        self.inner.scoped.lock().unwrap().entries.len()
+    }
+
+    /// Returns whether the entry has gone unused for longer than the idle TTL.
+    fn is_idle(&self, entry: &ScopedEntry, now: SystemTime) -> bool {
+        now.duration_since(entry.last_used)
+            .is_ok_and(|idle| idle > self.inner.scoped_idle_ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{access_key_id, manual_time, StubSts, DEFAULT_LIFETIME};
+    use crate::{Overrides, ScopedStats};
+    use aws_types::os_shim_internal::TimeSource;
+    use std::time::Duration;
+
+    fn policy(name: &str) -> Overrides {
+        Overrides::default().policy(format!("{{\"Sid\":\"{}\"}}", name))
+    }
+
+    /// Returns the hits and misses of the entry with the policy of `name`, if any.
+    fn usage(stats: &[ScopedStats], name: &str) -> Option<(u64, u64)> {
+        let policy = policy(name).policy;
+        stats
+            .iter()
+            .find(|stats| stats.policy == policy)
+            .map(|stats| (stats.hits, stats.misses))
+    }
+
+    #[tokio::test]
+    async fn scoped_credentials_are_cached_per_scope() {
+        let sts = StubSts::new();
+        let provider = sts.provider();
+        let first = provider.get_scoped(policy("a")).await.unwrap();
+        assert_eq!(sts.last().param("Policy"), policy("a").policy);
+        let again = provider.get_scoped(policy("a")).await.unwrap();
+        assert_eq!(again.access_key_id(), first.access_key_id());
+        let other = provider.get_scoped(policy("b")).await.unwrap();
+        assert_eq!(other.access_key_id(), access_key_id(2));
+        assert_eq!(sts.calls(), 2);
+
+        let stats = provider.scoped_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(usage(&stats, "a"), Some((1, 1)));
+        assert_eq!(usage(&stats, "b"), Some((0, 1)));
+        // The scopes don't share the cache of the provider
+        assert_eq!(provider.current_access_key_id().await, None);
+    }
+
+    #[tokio::test]
+    async fn expired_scoped_credentials_are_refreshed() {
+        let mut clock = manual_time();
+        let sts = StubSts::with_time(TimeSource::manual(&clock));
+        // The entry isn't idle, only its credentials have expired
+        let provider = sts
+            .builder()
+            .scoped_idle_ttl(DEFAULT_LIFETIME * 2)
+            .build()
+            .unwrap();
+        provider.get_scoped(policy("a")).await.unwrap();
+        clock.advance(DEFAULT_LIFETIME);
+        let credentials = provider.get_scoped(policy("a")).await.unwrap();
+        assert_eq!(credentials.access_key_id(), access_key_id(2));
+        assert_eq!(usage(&provider.scoped_stats(), "a"), Some((0, 2)));
+    }
+
+    #[tokio::test]
+    async fn idle_scopes_are_evicted() {
+        let mut clock = manual_time();
+        let sts = StubSts::with_time(TimeSource::manual(&clock));
+        let provider = sts
+            .builder()
+            .scoped_idle_ttl(Duration::from_secs(60))
+            .build()
+            .unwrap();
+        provider.get_scoped(policy("a")).await.unwrap();
+        provider.get_scoped(policy("b")).await.unwrap();
+        clock.advance(Duration::from_secs(61));
+
+        // Read after the idle TTL, the entry starts over
+        let credentials = provider.get_scoped(policy("a")).await.unwrap();
+        assert_eq!(credentials.access_key_id(), access_key_id(3));
+        assert_eq!(usage(&provider.scoped_stats(), "a"), Some((0, 1)));
+        // Entries no longer read are evicted by prune
+        assert_eq!(provider.prune(), 1);
+        let stats = provider.scoped_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(usage(&stats, "b"), None);
+    }
+
+    #[tokio::test]
+    async fn prune_evicts_expired_scopes() {
+        let mut clock = manual_time();
+        let sts = StubSts::with_time(TimeSource::manual(&clock));
+        let provider = sts
+            .builder()
+            .scoped_idle_ttl(DEFAULT_LIFETIME * 2)
+            .build()
+            .unwrap();
+        provider.get_scoped(policy("a")).await.unwrap();
+        assert_eq!(provider.prune(), 0);
+        clock.advance(DEFAULT_LIFETIME);
+        assert_eq!(provider.prune(), 1);
+        assert!(provider.scoped_stats().is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_least_recently_used_scope_is_evicted_past_the_maximum() {
+        let sts = StubSts::new();
+        let provider = sts.builder().scoped_max_entries(2).build().unwrap();
+        provider.get_scoped(policy("a")).await.unwrap();
+        provider.get_scoped(policy("b")).await.unwrap();
+        // Reading "a" again makes "b" the least recently used
+        provider.get_scoped(policy("a")).await.unwrap();
+        assert_eq!(provider.scoped_entry_count(), 2);
+
+        provider.get_scoped(policy("c")).await.unwrap();
+        assert_eq!(provider.scoped_entry_count(), 2);
+        let stats = provider.scoped_stats();
+        assert_eq!(usage(&stats, "a"), Some((1, 1)));
+        assert_eq!(usage(&stats, "b"), None);
+        assert_eq!(usage(&stats, "c"), Some((0, 1)));
+        assert_eq!(sts.calls(), 3);
+    }
+}
@@ -0,0 +1,43 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// A task run in the background by the provider
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send + 'static>>;
+
+/// Runs the tasks of the provider that outlive the read that started them.
+///
+/// These are the background refreshes enabled by
+/// [`refresh_ahead`](crate::STSCredentialsProviderBuilder::refresh_ahead) and, with the `metrics`
+/// feature, the expiry gauge. Implement it to run these tasks on a runtime other than Tokio, and
+/// set it with [`spawner`](crate::STSCredentialsProviderBuilder::spawner).
+pub trait Spawn: Debug + Send + Sync {
+    /// Starts running `task` without waiting for it to complete.
+    fn spawn(&self, task: Task);
+}
+
+/// Spawns the tasks on the current Tokio runtime.
+///
+/// Spawning panics when called outside of a Tokio runtime.
+#[cfg(feature = "rt-tokio")]
+#[derive(Debug, Default)]
+pub struct TokioSpawn;
+
+#[cfg(feature = "rt-tokio")]
+impl Spawn for TokioSpawn {
+    fn spawn(&self, task: Task) {
+        tokio::spawn(task);
+    }
+}
+
+/// Returns the spawner used when none is set on the builder.
+#[cfg(feature = "rt-tokio")]
+pub(crate) fn default_spawner() -> Option<Arc<dyn Spawn>> {
+    Some(Arc::new(TokioSpawn))
+}
+
+#[cfg(not(feature = "rt-tokio"))]
+pub(crate) fn default_spawner() -> Option<Arc<dyn Spawn>> {
+    None
+}
@@ -0,0 +1,409 @@
+//! A stubbed STS endpoint, and the environment the tests build providers in.
+
+use crate::{STSCredentialsProvider, STSCredentialsProviderBuilder};
+use aws_smithy_async::rt::sleep::{AsyncSleep, Sleep};
+use aws_smithy_client::erase::DynConnector;
+use aws_smithy_http::body::SdkBody;
+use aws_smithy_http::result::ConnectorError;
+use aws_smithy_types::date_time::{DateTime, Format};
+use aws_types::os_shim_internal::{ManualTimeSource, TimeSource};
+use http::{HeaderMap, Uri};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Once};
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{env, fs};
+use tower::Service;
+use tracing::subscriber::DefaultGuard;
+use tracing_subscriber::fmt::format::FmtSpan;
+
+/// Role assumed by the providers of the tests
+pub(crate) const ROLE_ARN: &str = "arn:aws:iam::123456789012:role/primary";
+/// Region the tests call STS in
+pub(crate) const REGION: &str = "eu-west-3";
+/// Access key id of the source credentials set in the environment
+pub(crate) const SOURCE_ACCESS_KEY_ID: &str = "AKIDENVIRONMENT";
+/// Profile of the config file written for the tests
+pub(crate) const PROFILE: &str = "stub";
+/// Access key id of the source credentials of [`PROFILE`]
+pub(crate) const PROFILE_ACCESS_KEY_ID: &str = "AKIDPROFILE";
+/// Lifetime of the credentials returned when no reply is queued
+pub(crate) const DEFAULT_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// What the stub replies to a call.
+#[derive(Clone, Debug)]
+pub(crate) enum Reply {
+    /// Credentials valid for the given duration, according to the clock of the stub
+    Credentials(Duration),
+    /// Credentials valid for the given duration, without a session token
+    WithoutSessionToken(Duration),
+    /// Credentials expiring at the given time
+    ExpiringAt(SystemTime),
+    /// An error response of STS
+    Error {
+        status: u16,
+        code: &'static str,
+        message: String,
+    },
+}
+
+impl Reply {
+    /// Returns the `AccessDenied` error STS sends when the trust policy rejects the call.
+    pub(crate) fn access_denied(message: impl Into<String>) -> Self {
+        Reply::Error {
+            status: 403,
+            code: "AccessDenied",
+            message: message.into(),
+        }
+    }
+
+    /// Returns a client error that the SDK doesn't retry.
+    pub(crate) fn invalid(message: impl Into<String>) -> Self {
+        Reply::Error {
+            status: 400,
+            code: "ValidationError",
+            message: message.into(),
+        }
+    }
+}
+
+/// A request received by the stub.
+#[derive(Clone, Debug)]
+pub(crate) struct Received {
+    pub(crate) uri: Uri,
+    pub(crate) headers: HeaderMap,
+    pub(crate) body: String,
+}
+
+impl Received {
+    /// Returns the access key id the request was signed with.
+    pub(crate) fn signing_key_id(&self) -> String {
+        let authorization = self.headers["authorization"].to_str().unwrap();
+        let credential = authorization.split("Credential=").nth(1).unwrap();
+        credential.split('/').next().unwrap().to_string()
+    }
+
+    /// Returns the decoded value of a parameter of the form-encoded body.
+    pub(crate) fn param(&self, name: &str) -> Option<String> {
+        self.body.split('&').find_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            (decode(key) == name).then(|| decode(value))
+        })
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    replies: VecDeque<Reply>,
+    received: Vec<Received>,
+}
+
+/// A connector answering like STS, with credentials numbered after the calls.
+///
+/// Replies are served in the order they were queued, and once none is left, the stub returns
+/// credentials valid for [`DEFAULT_LIFETIME`]. The access key id of the credentials returned by
+/// the n-th call is given by [`access_key_id`].
+#[derive(Clone, Debug)]
+pub(crate) struct StubSts {
+    state: Arc<Mutex<State>>,
+    time: TimeSource,
+    delay: Duration,
+}
+
+impl StubSts {
+    pub(crate) fn new() -> Self {
+        Self::with_time(TimeSource::default())
+    }
+
+    /// Returns a stub computing expiries with the given clock.
+    pub(crate) fn with_time(time: TimeSource) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                replies: VecDeque::new(),
+                received: Vec::new(),
+            })),
+            time,
+            delay: Duration::ZERO,
+        }
+    }
+
+    /// Delays each reply, so that calls overlap.
+    pub(crate) fn delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Returns the requests received so far.
+    pub(crate) fn received(&self) -> Vec<Received> {
+        self.state.lock().unwrap().received.clone()
+    }
+
+    /// Returns the last request received.
+    pub(crate) fn last(&self) -> Received {
+        self.received().pop().expect("STS wasn't called")
+    }
+
+    /// Queues the replies to the next calls.
+    pub(crate) fn reply(&self, replies: impl IntoIterator<Item = Reply>) -> &Self {
+        self.state.lock().unwrap().replies.extend(replies);
+        self
+    }
+
+    /// Returns the number of calls received so far.
+    pub(crate) fn calls(&self) -> usize {
+        self.state.lock().unwrap().received.len()
+    }
+
+    /// Returns the connector to give to the builder.
+    pub(crate) fn connector(&self) -> DynConnector {
+        DynConnector::new(self.clone())
+    }
+
+    /// Returns a builder for [`ROLE_ARN`] calling this stub, with the clock of the stub.
+    pub(crate) fn builder(&self) -> STSCredentialsProviderBuilder {
+        setup_environment();
+        STSCredentialsProvider::builder(ROLE_ARN)
+            .connector(self.connector())
+            .time_source(self.time.clone())
+    }
+
+    /// Returns a provider for [`ROLE_ARN`] calling this stub, with the default settings.
+    pub(crate) fn provider(&self) -> STSCredentialsProvider {
+        self.builder().build().unwrap()
+    }
+
+    fn respond(&self, request: http::Request<SdkBody>) -> http::Response<SdkBody> {
+        let received = Received {
+            uri: request.uri().clone(),
+            headers: request.headers().clone(),
+            body: String::from_utf8(request.body().bytes().unwrap_or_default().to_vec()).unwrap(),
+        };
+        let action = received.param("Action").unwrap_or_default();
+        let (reply, call) = {
+            let mut state = self.state.lock().unwrap();
+            state.received.push(received);
+            let reply = state.replies.pop_front();
+            (reply, state.received.len())
+        };
+        let (expiry, session_token) = match reply.unwrap_or(Reply::Credentials(DEFAULT_LIFETIME)) {
+            Reply::Credentials(lifetime) => (self.time.now() + lifetime, true),
+            Reply::WithoutSessionToken(lifetime) => (self.time.now() + lifetime, false),
+            Reply::ExpiringAt(expiry) => (expiry, true),
+            Reply::Error {
+                status,
+                code,
+                message,
+            } => return error_response(status, code, &message),
+        };
+        credentials_response(&action, call, expiry, session_token)
+    }
+}
+
+impl Service<http::Request<SdkBody>> for StubSts {
+    type Response = http::Response<SdkBody>;
+    type Error = ConnectorError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<SdkBody>) -> Self::Future {
+        let response = self.respond(request);
+        let delay = self.delay;
+        Box::pin(async move {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// A sleep implementation recording the durations it is asked to sleep for.
+#[derive(Debug, Default)]
+pub(crate) struct RecordingSleep(Mutex<Vec<Duration>>);
+
+impl RecordingSleep {
+    pub(crate) fn durations(&self) -> Vec<Duration> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl AsyncSleep for RecordingSleep {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        self.0.lock().unwrap().push(duration);
+        Sleep::new(tokio::time::sleep(duration))
+    }
+}
+
+/// Log lines emitted while a [`capture_logs`] guard is alive.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl Logs {
+    /// Returns the lines logged so far that contain `pattern`.
+    pub(crate) fn matching(&self, pattern: &str) -> Vec<String> {
+        String::from_utf8(self.0.lock().unwrap().clone())
+            .unwrap()
+            .lines()
+            .filter(|line| line.contains(pattern))
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+impl io::Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Captures the events logged by the current thread until the guard is dropped.
+///
+/// The spans closed are logged along with their fields. The tests run on the single-threaded
+/// runtime of `tokio::test`, so this sees the whole test.
+pub(crate) fn capture_logs() -> (Logs, DefaultGuard) {
+    let logs = Logs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_ansi(false)
+        .with_span_events(FmtSpan::CLOSE)
+        .finish();
+    (logs, tracing::subscriber::set_default(subscriber))
+}
+
+/// Returns a manual clock, starting on a whole second as the expiries sent by STS.
+pub(crate) fn manual_time() -> ManualTimeSource {
+    ManualTimeSource::new(UNIX_EPOCH + Duration::from_secs(1_700_000_000))
+}
+
+/// Returns the access key id of the credentials returned by the `call`-th call, starting at 1.
+pub(crate) fn access_key_id(call: usize) -> String {
+    format!("ASIASTUB{:04}", call)
+}
+
+/// Returns the ARN of the assumed-role session returned by the stub.
+pub(crate) fn assumed_role_arn() -> String {
+    "arn:aws:sts::123456789012:assumed-role/primary/session".to_string()
+}
+
+fn credentials_response(
+    action: &str,
+    call: usize,
+    expiry: SystemTime,
+    session_token: bool,
+) -> http::Response<SdkBody> {
+    // Expiries are sent with a second precision, as by STS
+    let secs = expiry.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let expiration = DateTime::from_secs(secs as i64)
+        .fmt(Format::DateTime)
+        .unwrap();
+    let body = format!(
+        "<{action}Response xmlns=\"https://sts.amazonaws.com/doc/2011-06-15/\">\
+           <{action}Result>\
+             <AssumedRoleUser>\
+               <Arn>{arn}</Arn>\
+               <AssumedRoleId>AROASTUB:session</AssumedRoleId>\
+             </AssumedRoleUser>\
+             <Credentials>\
+               <AccessKeyId>{key}</AccessKeyId>\
+               <SecretAccessKey>secret-{call}</SecretAccessKey>\
+               {session_token}\
+               <Expiration>{expiration}</Expiration>\
+             </Credentials>\
+           </{action}Result>\
+           <ResponseMetadata><RequestId>request-{call}</RequestId></ResponseMetadata>\
+         </{action}Response>",
+        action = action,
+        arn = assumed_role_arn(),
+        key = access_key_id(call),
+        call = call,
+        session_token = match session_token {
+            true => format!("<SessionToken>token-{}</SessionToken>", call),
+            false => String::new(),
+        },
+        expiration = expiration,
+    );
+    http::Response::builder()
+        .status(200)
+        .body(SdkBody::from(body))
+        .unwrap()
+}
+
+fn error_response(status: u16, code: &str, message: &str) -> http::Response<SdkBody> {
+    let body = format!(
+        "<ErrorResponse xmlns=\"https://sts.amazonaws.com/doc/2011-06-15/\">\
+           <Error><Type>Sender</Type><Code>{}</Code><Message>{}</Message></Error>\
+           <RequestId>error</RequestId>\
+         </ErrorResponse>",
+        code, message
+    );
+    http::Response::builder()
+        .status(status)
+        .body(SdkBody::from(body))
+        .unwrap()
+}
+
+/// Decodes a component of a form-encoded body.
+fn decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap();
+                decoded.push(u8::from_str_radix(hex, 16).unwrap());
+                i += 3;
+            }
+            b'+' => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            byte => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(decoded).unwrap()
+}
+
+/// Sets the source credentials and the region the providers of the tests resolve.
+///
+/// The config files are replaced by one holding [`PROFILE`], so that the tests don't depend on
+/// those of the machine they run on.
+pub(crate) fn setup_environment() {
+    static SETUP: Once = Once::new();
+    SETUP.call_once(|| {
+        let dir = env::temp_dir().join(format!("sts-caching-provider-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("config");
+        fs::write(
+            &config,
+            format!(
+                "[profile {}]\naws_access_key_id = {}\naws_secret_access_key = secret\n",
+                PROFILE, PROFILE_ACCESS_KEY_ID
+            ),
+        )
+        .unwrap();
+        env::set_var("AWS_CONFIG_FILE", &config);
+        env::set_var("AWS_SHARED_CREDENTIALS_FILE", dir.join("credentials"));
+        env::set_var("AWS_REGION", REGION);
+        env::set_var("AWS_ACCESS_KEY_ID", SOURCE_ACCESS_KEY_ID);
+        env::set_var("AWS_SECRET_ACCESS_KEY", "secret");
+        env::set_var("AWS_EC2_METADATA_DISABLED", "true");
+        env::remove_var("AWS_PROFILE");
+        env::remove_var("AWS_SESSION_TOKEN");
+    });
+}
@@ -0,0 +1,1786 @@
+use super::*;
+use crate::testing::{self, access_key_id, manual_time, Reply, StubSts, DEFAULT_LIFETIME};
+use aws_types::os_shim_internal::{ManualTimeSource, TimeSource};
+use proptest::prelude::*;
+use std::time::UNIX_EPOCH;
+
+/// Seconds since the epoch of the latest `SystemTime` representable on all supported platforms
+const MAX_SYSTEM_TIME_SECS: u64 = i64::MAX as u64;
+
+/// Times spread over the usual range, and close to the maximum `SystemTime`.
+fn system_time() -> impl Strategy<Value = SystemTime> {
+    prop_oneof![
+        0..4_000_000_000u64,
+        MAX_SYSTEM_TIME_SECS - 1_000_000..=MAX_SYSTEM_TIME_SECS,
+    ]
+    .prop_map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Margins from zero to `Duration::MAX`, with a bias towards realistic values.
+fn margin() -> impl Strategy<Value = Duration> {
+    prop_oneof![
+        3 => (0..100_000u64).prop_map(Duration::from_secs),
+        1 => any::<u64>().prop_map(Duration::from_secs),
+        1 => Just(Duration::MAX),
+    ]
+}
+
+fn margins() -> impl Strategy<Value = Margins> {
+    (margin(), margin(), margin()).prop_map(|(cache_timeout, refresh_ahead, delivery_cushion)| {
+        Margins {
+            cache_timeout,
+            refresh_ahead,
+            delivery_cushion,
+        }
+    })
+}
+
+/// Returns `now` plus the margins, or `None` if it overflows.
+fn deadline(now: SystemTime, margins: &[Duration]) -> Option<SystemTime> {
+    margins
+        .iter()
+        .try_fold(now, |time, margin| time.checked_add(*margin))
+}
+
+proptest! {
+    #[test]
+    fn freshness_never_serves_within_cache_timeout(
+        expiry in system_time(),
+        now in system_time(),
+        margins in margins(),
+    ) {
+        let usable = deadline(now, &[margins.delivery_cushion, margins.cache_timeout])
+            .is_some_and(|deadline| expiry > deadline);
+        let result = freshness(expiry, now, margins);
+        prop_assert_eq!(result != Freshness::Stale, usable);
+    }
+
+    #[test]
+    fn freshness_is_only_fresh_beyond_refresh_ahead(
+        expiry in system_time(),
+        now in system_time(),
+        margins in margins(),
+    ) {
+        let beyond_refresh_ahead =
+            deadline(now, &[margins.delivery_cushion, margins.refresh_ahead])
+                .is_some_and(|deadline| expiry > deadline);
+        match freshness(expiry, now, margins) {
+            Freshness::Fresh => prop_assert!(beyond_refresh_ahead),
+            Freshness::RefreshAhead => prop_assert!(!beyond_refresh_ahead),
+            Freshness::Stale => {}
+        }
+    }
+}
+
+#[test]
+fn freshness_is_stale_when_the_margins_overflow() {
+    let margins = Margins {
+        cache_timeout: Duration::MAX,
+        refresh_ahead: Duration::MAX,
+        delivery_cushion: Duration::ZERO,
+    };
+    let now = UNIX_EPOCH + Duration::from_secs(MAX_SYSTEM_TIME_SECS);
+    assert_eq!(freshness(now, now, margins), Freshness::Stale);
+}
+
+#[tokio::test]
+async fn source_identity_is_sent_to_sts() {
+    let sts = StubSts::new();
+    let provider = sts.builder().source_identity("alice").build().unwrap();
+    provider.get_credentials().await.unwrap();
+    assert_eq!(sts.last().param("SourceIdentity").as_deref(), Some("alice"));
+}
+
+#[tokio::test]
+async fn current_access_key_id_reports_the_cached_credentials() {
+    let sts = StubSts::new();
+    let provider = sts.provider();
+    assert_eq!(provider.current_access_key_id().await, None);
+    provider.get_credentials().await.unwrap();
+    assert_eq!(
+        provider.current_access_key_id().await,
+        Some(access_key_id(1))
+    );
+    // Reading the key id doesn't call STS
+    assert_eq!(sts.calls(), 1);
+}
+
+#[test]
+fn account_id_is_extracted_from_arns() {
+    assert_eq!(
+        account_id_from_arn("arn:aws:sts::123456789012:assumed-role/role/session"),
+        Some("123456789012")
+    );
+    assert_eq!(
+        account_id_from_arn("arn:aws-cn:iam::210987654321:role/path/role"),
+        Some("210987654321")
+    );
+    assert_eq!(account_id_from_arn("arn:aws:s3:::bucket"), None);
+    assert_eq!(account_id_from_arn("not an arn"), None);
+}
+
+#[tokio::test]
+async fn account_id_is_that_of_the_assumed_role() {
+    let sts = StubSts::new();
+    let provider = sts.provider();
+    assert_eq!(provider.account_id().await, None);
+    provider.get_credentials().await.unwrap();
+    assert_eq!(provider.account_id().await.as_deref(), Some("123456789012"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fail_fast_only_skips_refreshing_while_another_refresh_is_in_flight() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock)).delay(Duration::from_millis(200));
+    let provider = Arc::new(
+        sts.builder()
+            .in_flight_policy(InFlightPolicy::FailFast)
+            .build()
+            .unwrap(),
+    );
+    provider.get_credentials().await.unwrap();
+    // Stale, but not expired yet
+    clock.advance(DEFAULT_LIFETIME - Duration::from_secs(30));
+
+    let refresh = {
+        let provider = provider.clone();
+        tokio::spawn(async move { provider.get_credentials().await })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(1));
+    // The accessors don't go through the lock held by the refresh, so they can't pass for one
+    let key = tokio::time::timeout(Duration::from_millis(50), provider.current_access_key_id());
+    assert_eq!(key.await.unwrap(), Some(access_key_id(1)));
+    let expiry = tokio::time::timeout(Duration::from_millis(50), provider.cached_expiry());
+    assert!(expiry.await.unwrap().is_some());
+
+    let creds = refresh.await.unwrap().unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(2));
+    assert_eq!(sts.calls(), 2);
+}
+
+#[tokio::test]
+async fn stale_reads_wait_for_the_background_refresh() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock)).delay(Duration::from_millis(200));
+    let provider = sts
+        .builder()
+        .refresh_ahead(Duration::from_secs(300))
+        .build()
+        .unwrap();
+    provider.get_credentials().await.unwrap();
+
+    clock.advance(DEFAULT_LIFETIME - Duration::from_secs(200));
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(1));
+    // Let the background refresh call STS, then make the cached credentials stale
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    clock.advance(Duration::from_secs(170));
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(2));
+    assert_eq!(sts.calls(), 2);
+}
+
+#[tokio::test]
+async fn credentials_are_refreshed_in_the_background_ahead_of_staleness() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    let provider = sts
+        .builder()
+        .refresh_ahead(Duration::from_secs(300))
+        .build()
+        .unwrap();
+    provider.get_credentials().await.unwrap();
+
+    clock.advance(DEFAULT_LIFETIME - Duration::from_secs(200));
+    for _ in 0..3 {
+        let creds = provider.get_credentials().await.unwrap();
+        assert_eq!(creds.access_key_id(), access_key_id(1));
+    }
+    while provider.inner.refreshing.load(Ordering::Acquire) {
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(sts.calls(), 2);
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(2));
+}
+
+/// Runs the tasks on Tokio, counting them.
+#[derive(Debug, Default)]
+struct CountingSpawn(AtomicUsize);
+
+impl Spawn for CountingSpawn {
+    fn spawn(&self, task: Task) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(task);
+    }
+}
+
+#[tokio::test]
+async fn background_refreshes_go_through_the_spawner() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    let spawner = Arc::new(CountingSpawn::default());
+    let provider = sts
+        .builder()
+        .refresh_ahead(Duration::from_secs(300))
+        .spawner(spawner.clone())
+        .build()
+        .unwrap();
+    provider.get_credentials().await.unwrap();
+    assert_eq!(spawner.0.load(Ordering::Relaxed), 0);
+
+    clock.advance(DEFAULT_LIFETIME - Duration::from_secs(200));
+    provider.get_credentials().await.unwrap();
+    while provider.inner.refreshing.load(Ordering::Acquire) {
+        tokio::task::yield_now().await;
+    }
+    assert_eq!(spawner.0.load(Ordering::Relaxed), 1);
+    assert_eq!(sts.calls(), 2);
+}
+
+#[tokio::test]
+async fn sts_is_called_through_the_configured_connector() {
+    let sts = StubSts::new();
+    let provider = sts.provider();
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(1));
+    let request = sts.last();
+    assert_eq!(request.param("Action").as_deref(), Some("AssumeRole"));
+    assert_eq!(request.param("RoleArn").as_deref(), Some(testing::ROLE_ARN));
+}
+
+#[tokio::test]
+async fn build_and_assume_populates_the_cache() {
+    let sts = StubSts::new();
+    let provider = sts.builder().build_and_assume().await.unwrap();
+    assert_eq!(sts.calls(), 1);
+    assert_eq!(
+        provider.current_access_key_id().await,
+        Some(access_key_id(1))
+    );
+}
+
+#[tokio::test]
+async fn build_and_assume_fails_if_the_role_cant_be_assumed() {
+    let sts = StubSts::new();
+    sts.reply([Reply::access_denied(
+        "not authorized to perform sts:AssumeRole",
+    )]);
+    let error = sts.builder().build_and_assume().await.unwrap_err();
+    assert!(
+        error.to_string().contains("AssumeRole was denied"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn build_and_assume_reports_invalid_options() {
+    let sts = StubSts::new();
+    let result = sts.builder().provider_name("").build_and_assume().await;
+    assert!(matches!(
+        result,
+        Err(CredentialsError::InvalidConfiguration { .. })
+    ));
+    assert_eq!(sts.calls(), 0);
+}
+
+#[tokio::test]
+async fn the_delivery_cushion_extends_the_staleness_threshold() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    let provider = sts
+        .builder()
+        .cache_timeout(Duration::from_secs(60))
+        .delivery_cushion(Duration::from_secs(120))
+        .build()
+        .unwrap();
+    provider.get_credentials().await.unwrap();
+
+    // Fresh for cache_timeout alone, but not once the cushion is added
+    clock.advance(DEFAULT_LIFETIME - Duration::from_secs(150));
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(2));
+
+    clock.advance(DEFAULT_LIFETIME - Duration::from_secs(200));
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(2));
+    assert_eq!(sts.calls(), 2);
+}
+
+#[tokio::test]
+async fn sts_is_called_with_the_environment_credentials_by_default() {
+    let sts = StubSts::new();
+    sts.provider().get_credentials().await.unwrap();
+    assert_eq!(sts.last().signing_key_id(), testing::SOURCE_ACCESS_KEY_ID);
+}
+
+#[tokio::test]
+async fn sts_is_called_with_the_credentials_of_the_source_profile() {
+    let sts = StubSts::new();
+    let provider = sts
+        .builder()
+        .source_profile(testing::PROFILE)
+        .build()
+        .unwrap();
+    provider.get_credentials().await.unwrap();
+    assert_eq!(sts.last().signing_key_id(), testing::PROFILE_ACCESS_KEY_ID);
+}
+
+#[tokio::test]
+async fn an_unknown_source_profile_is_reported() {
+    let sts = StubSts::new();
+    let provider = sts.builder().source_profile("missing").build().unwrap();
+    let error = provider.get_credentials().await.unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("source credentials used to call STS could not be loaded"),
+        "{}",
+        error
+    );
+    assert_eq!(sts.calls(), 0);
+}
+
+#[tokio::test]
+async fn overrides_replace_the_parameters_sent_to_sts() {
+    let sts = StubSts::new();
+    let provider = sts
+        .builder()
+        .session_name("base")
+        .tag("team", "platform")
+        .build()
+        .unwrap();
+    let overridden = provider.with_overrides(
+        Overrides::default()
+            .role_arn("arn:aws:iam::123456789012:role/tenant")
+            .session_name("tenant")
+            .tag("tenant", "42")
+            .policy("{}"),
+    );
+    overridden.get_credentials().await.unwrap();
+    let request = sts.last();
+    assert_eq!(
+        request.param("RoleArn").as_deref(),
+        Some("arn:aws:iam::123456789012:role/tenant")
+    );
+    assert_eq!(request.param("RoleSessionName").as_deref(), Some("tenant"));
+    assert_eq!(
+        request.param("Tags.member.1.Key").as_deref(),
+        Some("tenant")
+    );
+    assert_eq!(request.param("Tags.member.2.Key"), None);
+    assert_eq!(request.param("Policy").as_deref(), Some("{}"));
+}
+
+#[tokio::test]
+async fn overridden_providers_share_the_client_but_not_the_cache() {
+    let sts = StubSts::new();
+    let provider = sts.builder().max_lifetime_calls(2).build().unwrap();
+    let overridden = provider.with_overrides(Overrides::default().session_name("other"));
+
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(1));
+    let creds = overridden.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(2));
+    // Both caches are populated, and the count of calls is shared
+    provider.get_credentials().await.unwrap();
+    overridden.get_credentials().await.unwrap();
+    assert_eq!(sts.calls(), 2);
+    assert_eq!(provider.inner.sts_calls.load(Ordering::Relaxed), 2);
+    // Unset overrides keep the parameters of the original provider
+    assert_eq!(
+        sts.last().param("RoleArn").as_deref(),
+        Some(testing::ROLE_ARN)
+    );
+}
+
+#[tokio::test]
+async fn credentials_expired_when_obtained_are_held_before_calling_sts_again() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    let expired = clock.now() - Duration::from_secs(10);
+    sts.reply([Reply::ExpiringAt(expired), Reply::ExpiringAt(expired)]);
+    let provider = sts.provider();
+
+    for _ in 0..3 {
+        let creds = provider.get_credentials().await.unwrap();
+        assert_eq!(creds.access_key_id(), access_key_id(1));
+    }
+    assert_eq!(sts.calls(), 1);
+
+    clock.advance(EXPIRED_LOAD_HOLD);
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(2));
+    assert_eq!(provider.inner.stale_loads.load(Ordering::Relaxed), 2);
+    // Credentials that are valid again are cached as usual
+    clock.advance(EXPIRED_LOAD_HOLD);
+    provider.get_credentials().await.unwrap();
+    provider.get_credentials().await.unwrap();
+    assert_eq!(sts.calls(), 3);
+}
+
+#[tokio::test]
+async fn the_external_id_is_redacted_from_errors() {
+    let sts = StubSts::new();
+    sts.reply([Reply::access_denied(
+        "external id s3cr3t-id doesn't match the trust policy",
+    )]);
+    let provider = sts.builder().external_id("s3cr3t-id").build().unwrap();
+    let error = provider.get_credentials().await.unwrap_err();
+    assert_eq!(sts.last().param("ExternalId").as_deref(), Some("s3cr3t-id"));
+    assert!(!error.to_string().contains("s3cr3t-id"), "{}", error);
+    assert!(!format!("{:?}", error).contains("s3cr3t-id"), "{:?}", error);
+    assert!(error.to_string().contains(REDACTED), "{}", error);
+}
+
+#[test]
+fn the_external_id_is_redacted_from_debug_output() {
+    let sts = StubSts::new();
+    let provider = sts.builder().external_id("s3cr3t-id").build().unwrap();
+    assert!(!format!("{:?}", provider).contains("s3cr3t-id"));
+    let overrides = Overrides::default().external_id("s3cr3t-id");
+    assert!(!format!("{:?}", overrides).contains("s3cr3t-id"));
+}
+
+#[tokio::test]
+async fn is_expiring_soon_follows_the_staleness_of_the_cache() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    let provider = sts
+        .builder()
+        .cache_timeout(Duration::from_secs(60))
+        .build()
+        .unwrap();
+    assert!(provider.is_expiring_soon(Duration::ZERO).await);
+    provider.get_credentials().await.unwrap();
+
+    let until_stale = DEFAULT_LIFETIME - Duration::from_secs(60);
+    assert!(!provider.is_expiring_soon(Duration::ZERO).await);
+    assert!(
+        !provider
+            .is_expiring_soon(until_stale - Duration::from_secs(1))
+            .await
+    );
+    assert!(provider.is_expiring_soon(until_stale).await);
+    // Too long to be added to the current time
+    assert!(provider.is_expiring_soon(Duration::MAX).await);
+
+    clock.advance(until_stale);
+    assert!(provider.is_expiring_soon(Duration::ZERO).await);
+}
+
+#[tokio::test]
+async fn is_expiring_soon_uses_the_reduced_margin_of_short_lifetimes() {
+    let clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    sts.reply([Reply::Credentials(Duration::from_secs(900))]);
+    let provider = sts
+        .builder()
+        .cache_timeout(Duration::from_secs(1200))
+        .build()
+        .unwrap();
+    provider.get_credentials().await.unwrap();
+    // Stale for cache_timeout, but the credentials are served until halfway through their lifetime
+    assert!(!provider.is_expiring_soon(Duration::from_secs(400)).await);
+    assert!(provider.is_expiring_soon(Duration::from_secs(450)).await);
+}
+
+/// Returns the error STS sends when the source credentials have expired.
+fn expired_token() -> Reply {
+    Reply::Error {
+        status: 400,
+        code: "ExpiredToken",
+        message: "The security token included in the request is expired".to_string(),
+    }
+}
+
+#[tokio::test]
+async fn assume_role_is_retried_once_when_the_source_credentials_expired() {
+    let sts = StubSts::new();
+    sts.reply([expired_token()]);
+    let provider = sts.provider();
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(2));
+    assert_eq!(sts.calls(), 2);
+
+    let sts = StubSts::new();
+    sts.reply([expired_token(), expired_token(), expired_token()]);
+    let provider = sts.provider();
+    provider.get_credentials().await.unwrap_err();
+    assert_eq!(sts.calls(), 2);
+}
+
+#[tokio::test]
+async fn session_tags_are_sent_sorted_by_key() {
+    let sts = StubSts::new();
+    let provider = sts
+        .builder()
+        .tags(HashMap::from([
+            ("team".to_string(), "platform".to_string()),
+            ("cost-center".to_string(), "42".to_string()),
+        ]))
+        .build()
+        .unwrap();
+    provider.get_credentials().await.unwrap();
+    let request = sts.last();
+    assert_eq!(
+        request.param("Tags.member.1.Key").as_deref(),
+        Some("cost-center")
+    );
+    assert_eq!(request.param("Tags.member.1.Value").as_deref(), Some("42"));
+    assert_eq!(request.param("Tags.member.2.Key").as_deref(), Some("team"));
+    assert_eq!(
+        request.param("Tags.member.2.Value").as_deref(),
+        Some("platform")
+    );
+}
+
+#[tokio::test]
+async fn wait_for_credentials_retries_until_sts_succeeds() {
+    let sts = StubSts::new();
+    sts.reply([Reply::invalid("first"), Reply::invalid("second")]);
+    let provider = sts.provider();
+    let creds = provider
+        .wait_for_credentials(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(3));
+    assert_eq!(sts.calls(), 3);
+}
+
+#[tokio::test]
+async fn wait_for_credentials_returns_the_last_error_at_the_deadline() {
+    let sts = StubSts::new();
+    sts.reply((0..10).map(|i| Reply::invalid(format!("attempt {}", i))));
+    let provider = sts.provider();
+    let error = provider
+        .wait_for_credentials(Duration::from_millis(250))
+        .await
+        .unwrap_err();
+    // Attempts at 0, 100 and 250 milliseconds, the last one possibly cut by the deadline
+    let calls = sts.calls();
+    assert!((2..=3).contains(&calls), "{} calls", calls);
+    let last = format!("attempt {}", calls - 1);
+    assert!(format!("{:?}", error).contains(&last), "{:?}", error);
+}
+
+#[tokio::test]
+async fn wait_for_credentials_accepts_unrepresentable_deadlines() {
+    let sts = StubSts::new();
+    sts.reply([Reply::invalid("first")]);
+    let provider = sts.provider();
+    let creds = provider.wait_for_credentials(Duration::MAX).await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(2));
+}
+
+const FALLBACK_ARN: &str = "arn:aws:iam::123456789012:role/fallback";
+
+#[tokio::test]
+async fn the_fallback_role_is_assumed_and_then_tried_first() {
+    let sts = StubSts::new();
+    sts.reply([Reply::access_denied("primary denied")]);
+    let provider = sts
+        .builder()
+        .fallback_role(FallbackRole::new(FALLBACK_ARN).session_name("fallback"))
+        .build()
+        .unwrap();
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(2));
+    let roles: Vec<_> = sts
+        .received()
+        .iter()
+        .map(|request| request.param("RoleArn").unwrap())
+        .collect();
+    assert_eq!(roles, [testing::ROLE_ARN, FALLBACK_ARN]);
+    assert_eq!(
+        sts.last().param("RoleSessionName").as_deref(),
+        Some("fallback")
+    );
+
+    provider.force_rotate().await.unwrap();
+    assert_eq!(sts.calls(), 3);
+    assert_eq!(sts.last().param("RoleArn").as_deref(), Some(FALLBACK_ARN));
+}
+
+#[tokio::test]
+async fn the_error_of_the_primary_role_is_returned_when_no_role_can_be_assumed() {
+    let sts = StubSts::new();
+    sts.reply([
+        Reply::access_denied("primary denied"),
+        Reply::access_denied("fallback denied"),
+    ]);
+    let provider = sts
+        .builder()
+        .fallback_role(FallbackRole::new(FALLBACK_ARN))
+        .build()
+        .unwrap();
+    let error = provider.get_credentials().await.unwrap_err();
+    assert!(
+        format!("{:?}", error).contains("primary denied"),
+        "{:?}",
+        error
+    );
+    assert_eq!(sts.calls(), 2);
+}
+
+#[tokio::test]
+async fn fallback_roles_count_against_the_call_cap() {
+    let sts = StubSts::new();
+    sts.reply([Reply::access_denied("primary denied")]);
+    let provider = sts
+        .builder()
+        .fallback_role(FallbackRole::new(FALLBACK_ARN))
+        .max_lifetime_calls(1)
+        .build()
+        .unwrap();
+    let error = provider.get_credentials().await.unwrap_err();
+    assert!(
+        format!("{:?}", error).contains("primary denied"),
+        "{:?}",
+        error
+    );
+    // The fallback role isn't assumed past the cap
+    assert_eq!(sts.calls(), 1);
+    assert_eq!(provider.inner.sts_calls.load(Ordering::Relaxed), 1);
+}
+
+#[cfg(not(feature = "sts-global-endpoint"))]
+#[tokio::test]
+async fn sts_is_called_on_the_regional_endpoint() {
+    let sts = StubSts::new();
+    sts.provider().get_credentials().await.unwrap();
+    let host = format!("sts.{}.amazonaws.com", testing::REGION);
+    assert_eq!(sts.last().uri.host(), Some(host.as_str()));
+}
+
+#[cfg(feature = "sts-global-endpoint")]
+#[tokio::test]
+async fn sts_is_called_on_the_global_endpoint() {
+    let sts = StubSts::new();
+    sts.provider().get_credentials().await.unwrap();
+    assert_eq!(sts.last().uri.host(), Some("sts.amazonaws.com"));
+}
+
+/// Returns credentials with the given key id, expiring at `expiry`.
+fn injected(access_key_id: &str, expiry: Option<SystemTime>) -> Credentials {
+    Credentials::new(access_key_id, "secret", None, expiry, "test")
+}
+
+#[tokio::test]
+async fn injected_credentials_are_returned_until_stale() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    let provider = sts.provider();
+    let expiry = clock.now() + Duration::from_secs(600);
+    provider
+        .set_cached_credentials(injected("AKIDINJECTED", Some(expiry)))
+        .await
+        .unwrap();
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), "AKIDINJECTED");
+    assert_eq!(sts.calls(), 0);
+
+    clock.advance(Duration::from_secs(550));
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(1));
+}
+
+#[tokio::test]
+async fn injected_credentials_must_have_an_expiry() {
+    let sts = StubSts::new();
+    let provider = sts.provider();
+    provider
+        .set_cached_credentials(injected("AKIDINJECTED", None))
+        .await
+        .unwrap_err();
+    assert_eq!(provider.current_access_key_id().await, None);
+}
+
+#[tokio::test]
+async fn short_lifetimes_are_served_until_halfway_through() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    sts.reply([Reply::Credentials(Duration::from_secs(900))]);
+    let provider = sts
+        .builder()
+        .cache_timeout(Duration::from_secs(1200))
+        .build()
+        .unwrap();
+    provider.get_credentials().await.unwrap();
+    clock.advance(Duration::from_secs(400));
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(1));
+    assert_eq!(sts.calls(), 1);
+
+    clock.advance(Duration::from_secs(50));
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(2));
+}
+
+#[tokio::test]
+async fn short_lifetimes_can_be_rejected() {
+    let clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    sts.reply([Reply::Credentials(Duration::from_secs(900))]);
+    let provider = sts
+        .builder()
+        .cache_timeout(Duration::from_secs(1200))
+        .short_lifetime_policy(ShortLifetimePolicy::Fail)
+        .build()
+        .unwrap();
+    let error = provider.get_credentials().await.unwrap_err();
+    assert!(
+        error
+            .to_string()
+            .contains("only valid for 900s, which doesn't leave room for cache_timeout (1200s)"),
+        "{}",
+        error
+    );
+    assert_eq!(provider.current_access_key_id().await, None);
+}
+
+/// Sleeps with Tokio, recording the durations it was asked to sleep for.
+#[tokio::test]
+async fn waits_go_through_the_sleep_implementation() {
+    let sts = StubSts::new();
+    sts.reply([Reply::invalid("first")]);
+    let sleep = Arc::new(testing::RecordingSleep::default());
+    let provider = sts.builder().sleep_impl(sleep.clone()).build().unwrap();
+    provider
+        .wait_for_credentials(Duration::from_secs(10))
+        .await
+        .unwrap();
+    assert!(sleep.durations().contains(&INITIAL_WAIT_BACKOFF));
+}
+
+/// Returns the reply of credentials issued `ahead` in the future of `clock`.
+fn issued_ahead_of(clock: &ManualTimeSource, ahead: Duration) -> Reply {
+    Reply::ExpiringAt(clock.now() + DEFAULT_SESSION_DURATION + ahead)
+}
+
+#[tokio::test]
+async fn loads_wait_for_credentials_issued_slightly_in_the_future() {
+    let clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    sts.reply([issued_ahead_of(&clock, Duration::from_secs(1))]);
+    let sleep = Arc::new(testing::RecordingSleep::default());
+    let provider = sts.builder().sleep_impl(sleep.clone()).build().unwrap();
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(1));
+    assert!(sleep.durations().contains(&Duration::from_secs(1)));
+}
+
+#[tokio::test]
+async fn loads_dont_wait_for_credentials_issued_far_in_the_future() {
+    let clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    sts.reply([issued_ahead_of(&clock, Duration::from_secs(60))]);
+    let sleep = Arc::new(testing::RecordingSleep::default());
+    let provider = sts.builder().sleep_impl(sleep.clone()).build().unwrap();
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(1));
+    assert!(!sleep.durations().contains(&Duration::from_secs(60)));
+    assert_eq!(sts.calls(), 1);
+}
+
+#[tokio::test]
+async fn credentials_come_with_the_metadata_of_their_call() {
+    let clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    let provider = sts.builder().session_name("session").build().unwrap();
+    let assumed = provider.get_credentials_with_metadata().await.unwrap();
+    assert_eq!(assumed.credentials.access_key_id(), access_key_id(1));
+    assert_eq!(assumed.expiry, clock.now() + DEFAULT_LIFETIME);
+    assert_eq!(assumed.assumed_role_arn, Some(testing::assumed_role_arn()));
+    assert_eq!(assumed.session_name.as_deref(), Some("session"));
+    // Served from the cache like the credentials themselves
+    provider.get_credentials_with_metadata().await.unwrap();
+    assert_eq!(sts.calls(), 1);
+}
+
+#[tokio::test]
+async fn force_rotate_returns_the_old_and_new_credentials() {
+    let sts = StubSts::new();
+    let provider = sts.provider();
+    let (old, new) = provider.force_rotate().await.unwrap();
+    assert!(old.is_none());
+    assert_eq!(new.access_key_id(), access_key_id(1));
+
+    let (old, new) = provider.force_rotate().await.unwrap();
+    assert_eq!(old.unwrap().access_key_id(), access_key_id(1));
+    assert_eq!(new.access_key_id(), access_key_id(2));
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(2));
+}
+
+#[tokio::test]
+async fn a_failed_rotation_leaves_the_cache_untouched() {
+    let sts = StubSts::new();
+    let provider = sts.provider();
+    provider.get_credentials().await.unwrap();
+    sts.reply([Reply::invalid("rotation failed")]);
+    provider.force_rotate().await.unwrap_err();
+    let creds = provider.get_credentials().await.unwrap();
+    assert_eq!(creds.access_key_id(), access_key_id(1));
+    assert_eq!(sts.calls(), 2);
+}
+
+#[tokio::test]
+async fn a_failed_refresh_clears_the_cache_unless_disabled() {
+    for clear in [true, false] {
+        let mut clock = manual_time();
+        let sts = StubSts::with_time(TimeSource::manual(&clock));
+        let provider = sts.builder().clear_cache_on_error(clear).build().unwrap();
+        provider.get_credentials().await.unwrap();
+        clock.advance(DEFAULT_LIFETIME);
+        sts.reply([Reply::invalid("refresh failed"), Reply::invalid("again")]);
+        provider.get_credentials().await.unwrap_err();
+
+        let kept = provider.current_access_key_id().await;
+        assert_eq!(kept.is_some(), !clear);
+        // The stale credentials are never returned
+        provider.get_credentials().await.unwrap_err();
+        assert_eq!(sts.calls(), 3);
+    }
+}
+
+#[tokio::test]
+async fn unique_session_names_get_a_suffix_within_the_length_limit() {
+    let long = "s".repeat(70);
+    let sts = StubSts::new();
+    let provider = sts
+        .builder()
+        .session_name(&long)
+        .unique_session_names(true)
+        .build()
+        .unwrap();
+    provider.get_credentials().await.unwrap();
+    provider.force_rotate().await.unwrap();
+
+    let names: Vec<_> = sts
+        .received()
+        .iter()
+        .map(|request| request.param("RoleSessionName").unwrap())
+        .collect();
+    assert_ne!(names[0], names[1]);
+    for name in &names {
+        assert_eq!(name.len(), 64);
+        let (base, suffix) = name.rsplit_once('-').unwrap();
+        assert!(long.starts_with(base));
+        assert_eq!(suffix.len(), 8);
+        assert!(suffix.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+}
+
+#[tokio::test]
+async fn session_names_are_sent_as_is_by_default() {
+    let sts = StubSts::new();
+    let provider = sts.builder().session_name("session").build().unwrap();
+    provider.get_credentials().await.unwrap();
+    assert_eq!(sts.last().param("RoleSessionName").unwrap(), "session");
+}
+
+#[tokio::test]
+async fn imds_failures_come_with_the_hop_limit_hint() {
+    // IMDS is disabled in the environment of the tests
+    let sts = StubSts::new();
+    let provider = sts.builder().imds_source().build().unwrap();
+    let error = provider.get_credentials().await.unwrap_err();
+    assert!(
+        error.to_string().contains("HttpPutResponseHopLimit"),
+        "{}",
+        error
+    );
+    assert_eq!(sts.calls(), 0);
+}
+
+#[tokio::test]
+async fn the_suggested_cache_timeout_follows_the_latency_of_sts() {
+    let sts = StubSts::new().delay(Duration::from_millis(100));
+    let provider = sts.provider();
+    assert_eq!(provider.suggested_cache_timeout(), None);
+
+    provider.get_credentials().await.unwrap();
+    let suggested = provider.suggested_cache_timeout().unwrap();
+    assert!(suggested >= SUGGESTED_CACHE_TIMEOUT_MARGIN + Duration::from_millis(100));
+    assert!(suggested < SUGGESTED_CACHE_TIMEOUT_MARGIN + Duration::from_secs(5));
+}
+
+#[tokio::test]
+async fn the_suggested_cache_timeout_ignores_rate_limiter_waits() {
+    let sts = StubSts::new();
+    let sleep = Arc::new(testing::RecordingSleep::default());
+    let provider = sts
+        .builder()
+        .rate_limiter(RateLimiter::new(std::num::NonZeroU32::new(2).unwrap()))
+        .sleep_impl(sleep.clone())
+        .build()
+        .unwrap();
+    provider.get_credentials().await.unwrap();
+    provider.force_rotate().await.unwrap();
+    // The third call waits for the limiter
+    provider.force_rotate().await.unwrap();
+    assert_eq!(sleep.durations().len(), 1, "{:?}", sleep.durations());
+    let suggested = provider.suggested_cache_timeout().unwrap();
+    assert!(
+        suggested < SUGGESTED_CACHE_TIMEOUT_MARGIN + Duration::from_millis(250),
+        "{:?}",
+        suggested
+    );
+}
+
+fn environment_ids(external_id: &str) -> EnvironmentIds {
+    EnvironmentIds {
+        external_id: Some(external_id.to_string()),
+        source_identity: Some(format!("{}-identity", external_id)),
+    }
+}
+
+#[tokio::test]
+async fn environments_select_the_ids_sent_to_sts() {
+    let sts = StubSts::new();
+    let provider = sts
+        .builder()
+        .environment("staging", environment_ids("staging"))
+        .environment("production", environment_ids("production"))
+        .select_environment("staging")
+        .build()
+        .unwrap();
+    provider.get_credentials().await.unwrap();
+    assert_eq!(sts.last().param("ExternalId").unwrap(), "staging");
+    assert_eq!(
+        sts.last().param("SourceIdentity").unwrap(),
+        "staging-identity"
+    );
+
+    let production = provider.for_environment("production").unwrap();
+    let credentials = production.get_credentials().await.unwrap();
+    assert_eq!(sts.last().param("ExternalId").unwrap(), "production");
+    // Each environment has its own cache
+    assert_eq!(credentials.access_key_id(), access_key_id(2));
+    assert_eq!(
+        provider.get_credentials().await.unwrap().access_key_id(),
+        access_key_id(1)
+    );
+    assert!(provider.for_environment("development").is_none());
+}
+
+#[test]
+fn selecting_an_undeclared_environment_fails() {
+    let error = StubSts::new()
+        .builder()
+        .environment("staging", environment_ids("staging"))
+        .select_environment("production")
+        .build()
+        .unwrap_err();
+    match error {
+        BuildError::UnknownEnvironment(environment) => assert_eq!(environment, "production"),
+        error => panic!("unexpected error: {:?}", error),
+    }
+}
+
+/// Returns the messages of the error and its sources, from the outermost.
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut messages = vec![error.to_string()];
+    let mut source = error.source();
+    while let Some(e) = source {
+        messages.push(e.to_string());
+        source = e.source();
+    }
+    messages.join(": ")
+}
+
+#[tokio::test]
+async fn disabled_regions_are_explained() {
+    let sts = StubSts::new();
+    sts.reply([Reply::Error {
+        status: 403,
+        code: "RegionDisabledException",
+        message: "STS is not activated in this region".to_string(),
+    }]);
+    let error = sts.provider().get_credentials().await.unwrap_err();
+    let chain = error_chain(&error);
+    assert!(chain.contains("Enable the region"), "{}", chain);
+    // The global endpoint is called through its pseudo-region
+    let region = match cfg!(feature = "sts-global-endpoint") {
+        true => "aws-global",
+        false => testing::REGION,
+    };
+    assert!(chain.contains(&format!("region {}", region)), "{}", chain);
+    // The error of STS is kept as the source
+    assert!(chain.contains("RegionDisabledException"), "{}", chain);
+}
+
+#[tokio::test]
+async fn shared_providers_load_through_the_provider() {
+    let sts = StubSts::new();
+    let shared = sts.provider().into_shared();
+    let credentials = credentials::ProvideCredentials::provide_credentials(&shared)
+        .await
+        .unwrap();
+    assert_eq!(credentials.access_key_id(), access_key_id(1));
+
+    let shared = SharedCredentialsProvider::from(sts.provider());
+    let credentials = credentials::ProvideCredentials::provide_credentials(&shared)
+        .await
+        .unwrap();
+    assert_eq!(credentials.access_key_id(), access_key_id(2));
+}
+
+#[tokio::test]
+async fn sessions_shorter_than_requested_are_reported_once() {
+    let (logs, _guard) = testing::capture_logs();
+    let clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    let provider = sts.builder().session_duration(43200).build().unwrap();
+    assert_eq!(provider.granted_lifetime().await, None);
+
+    provider.get_credentials().await.unwrap();
+    // The stub returns credentials valid for an hour
+    assert_eq!(provider.granted_lifetime().await, Some(DEFAULT_LIFETIME));
+    assert_eq!(sts.last().param("DurationSeconds").unwrap(), "43200");
+    provider.force_rotate().await.unwrap();
+    assert_eq!(logs.matching("shorter than the 43200s requested").len(), 1);
+}
+
+#[tokio::test]
+async fn sessions_granted_as_requested_are_not_reported() {
+    let (logs, _guard) = testing::capture_logs();
+    let sts = StubSts::new();
+    let provider = sts.builder().session_duration(3600).build().unwrap();
+    provider.get_credentials().await.unwrap();
+    assert!(logs.matching("shorter than").is_empty());
+}
+
+#[tokio::test]
+async fn the_post_load_hook_can_rewrite_the_credentials() {
+    let sts = StubSts::new();
+    let provider = sts
+        .builder()
+        .post_load(|credentials, output| {
+            assert_eq!(
+                output.assumed_role_user().unwrap().arn(),
+                Some(testing::assumed_role_arn().as_str())
+            );
+            Ok(Credentials::new(
+                "AKIDREWRITTEN",
+                credentials.secret_access_key(),
+                credentials.session_token().map(str::to_string),
+                credentials.expiry(),
+                "rewritten",
+            ))
+        })
+        .build()
+        .unwrap();
+    let credentials = provider.get_credentials().await.unwrap();
+    assert_eq!(credentials.access_key_id(), "AKIDREWRITTEN");
+    // The rewritten credentials are cached
+    provider.get_credentials().await.unwrap();
+    assert_eq!(sts.calls(), 1);
+}
+
+#[tokio::test]
+async fn credentials_rejected_by_the_post_load_hook_are_not_cached() {
+    let sts = StubSts::new();
+    let provider = sts
+        .builder()
+        .post_load(|_, _| Err(CredentialsError::provider_error("wrong account")))
+        .build()
+        .unwrap();
+    let error = provider.get_credentials().await.unwrap_err();
+    assert!(error_chain(&error).contains("wrong account"), "{}", error);
+    assert_eq!(provider.current_access_key_id().await, None);
+    provider.get_credentials().await.unwrap_err();
+    assert_eq!(sts.calls(), 2);
+}
+
+#[tokio::test]
+async fn the_post_load_hook_must_keep_an_expiry() {
+    let sts = StubSts::new();
+    let provider = sts
+        .builder()
+        .post_load(|credentials, _| {
+            Ok(Credentials::new(
+                credentials.access_key_id(),
+                credentials.secret_access_key(),
+                None,
+                None,
+                "no-expiry",
+            ))
+        })
+        .build()
+        .unwrap();
+    let error = provider.get_credentials().await.unwrap_err();
+    assert!(
+        error_chain(&error).contains("without an expiry"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn custom_endpoints_are_called_and_reported() {
+    let endpoint =
+        aws_sdk_sts::Endpoint::immutable(http::Uri::from_static("https://sts.proxy.internal"));
+    let sts = StubSts::new();
+    sts.reply([Reply::invalid("rejected by the proxy")]);
+    let provider = sts.builder().endpoint_resolver(endpoint).build().unwrap();
+    let error = provider.get_credentials().await.unwrap_err();
+    assert_eq!(sts.last().uri.host(), Some("sts.proxy.internal"));
+    assert!(
+        error_chain(&error).contains("endpoint https://sts.proxy.internal/"),
+        "{}",
+        error_chain(&error)
+    );
+
+    provider.get_credentials().await.unwrap();
+    assert_eq!(sts.last().uri.host(), Some("sts.proxy.internal"));
+}
+
+#[tokio::test]
+async fn assert_valid_for_checks_the_remaining_lifetime() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    let provider = sts.provider();
+    assert_eq!(
+        provider.assert_valid_for(Duration::ZERO).await.unwrap_err(),
+        "no credentials are cached"
+    );
+
+    let expiry = clock.now() + Duration::from_secs(600);
+    provider
+        .set_cached_credentials(injected("AKIDINJECTED", Some(expiry)))
+        .await
+        .unwrap();
+    provider
+        .assert_valid_for(Duration::from_secs(600))
+        .await
+        .unwrap();
+    assert_eq!(
+        provider
+            .assert_valid_for(Duration::from_secs(601))
+            .await
+            .unwrap_err(),
+        "the cached credentials expire in 600s, less than the required 601s"
+    );
+
+    clock.advance(Duration::from_secs(610));
+    assert_eq!(
+        provider.assert_valid_for(Duration::ZERO).await.unwrap_err(),
+        "the cached credentials expired 10s ago"
+    );
+    // The checks never call STS
+    assert_eq!(sts.calls(), 0);
+}
+
+#[tokio::test]
+async fn session_tokens_are_handled_according_to_the_policy() {
+    let sts = StubSts::new();
+    let passthrough = sts.provider();
+    let credentials = passthrough.get_credentials().await.unwrap();
+    assert_eq!(credentials.session_token(), Some("token-1"));
+    sts.reply([Reply::WithoutSessionToken(DEFAULT_LIFETIME)]);
+    let (_, credentials) = passthrough.force_rotate().await.unwrap();
+    assert_eq!(credentials.session_token(), None);
+
+    let omit = sts
+        .builder()
+        .session_token_policy(SessionTokenPolicy::Omit)
+        .build()
+        .unwrap();
+    let credentials = omit.get_credentials().await.unwrap();
+    assert_eq!(credentials.session_token(), None);
+    assert_eq!(omit.has_session_token().await, Some(false));
+
+    let require = sts
+        .builder()
+        .session_token_policy(SessionTokenPolicy::Require)
+        .build()
+        .unwrap();
+    let credentials = require.get_credentials().await.unwrap();
+    assert_eq!(credentials.session_token(), Some("token-4"));
+    sts.reply([Reply::WithoutSessionToken(DEFAULT_LIFETIME)]);
+    let error = require.force_rotate().await.unwrap_err();
+    assert!(
+        error_chain(&error).contains("without a session token"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn reads_timing_out_on_the_lock_return_the_stale_credentials() {
+    let clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock)).delay(Duration::from_millis(300));
+    let provider = sts
+        .builder()
+        .lock_timeout(Duration::from_millis(50))
+        .build()
+        .unwrap();
+    let expiry = clock.now() + Duration::from_secs(10);
+    provider
+        .set_cached_credentials(injected("AKIDSTALE", Some(expiry)))
+        .await
+        .unwrap();
+
+    let (refreshed, waiting) = tokio::join!(provider.get_credentials(), async {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        provider.get_credentials().await
+    });
+    assert_eq!(refreshed.unwrap().access_key_id(), access_key_id(1));
+    assert_eq!(waiting.unwrap().access_key_id(), "AKIDSTALE");
+    assert_eq!(sts.calls(), 1);
+}
+
+#[tokio::test]
+async fn reads_timing_out_on_the_lock_fail_once_the_credentials_expired() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock)).delay(Duration::from_millis(300));
+    let provider = sts
+        .builder()
+        .lock_timeout(Duration::from_millis(50))
+        .build()
+        .unwrap();
+    let expiry = clock.now() + Duration::from_secs(10);
+    provider
+        .set_cached_credentials(injected("AKIDSTALE", Some(expiry)))
+        .await
+        .unwrap();
+
+    let (refreshed, waiting, _) = tokio::join!(
+        provider.get_credentials(),
+        async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            provider.get_credentials().await
+        },
+        async {
+            // The stale credentials expire while the second read waits
+            tokio::time::sleep(Duration::from_millis(40)).await;
+            clock.advance(Duration::from_secs(20));
+        }
+    );
+    refreshed.unwrap();
+    let error = waiting.unwrap_err();
+    assert!(
+        error.to_string().contains("timed out after 50ms"),
+        "{}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn refresh_if_matches_only_replaces_the_rejected_key() {
+    let sts = StubSts::new();
+    let provider = sts.provider();
+    provider.get_credentials().await.unwrap();
+
+    assert!(!provider.refresh_if_matches("AKIDOTHER").await.unwrap());
+    assert_eq!(sts.calls(), 1);
+    assert!(provider
+        .refresh_if_matches(&access_key_id(1))
+        .await
+        .unwrap());
+    assert_eq!(
+        provider.current_access_key_id().await,
+        Some(access_key_id(2))
+    );
+    // Concurrent calls for the same key refresh once
+    let rejected = access_key_id(2);
+    let (first, second) = tokio::join!(
+        provider.refresh_if_matches(&rejected),
+        provider.refresh_if_matches(&rejected)
+    );
+    assert!(first.unwrap() ^ second.unwrap());
+    assert_eq!(sts.calls(), 3);
+}
+
+#[tokio::test]
+async fn a_failed_refresh_if_matches_drops_the_rejected_key() {
+    let sts = StubSts::new();
+    let provider = sts.provider();
+    provider.get_credentials().await.unwrap();
+    sts.reply([Reply::invalid("refresh failed")]);
+    provider
+        .refresh_if_matches(&access_key_id(1))
+        .await
+        .unwrap_err();
+    assert_eq!(provider.current_access_key_id().await, None);
+    // Nothing matches an empty cache
+    assert!(!provider
+        .refresh_if_matches(&access_key_id(1))
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn credentials_carry_the_provider_name() {
+    let sts = StubSts::new();
+    let credentials = sts.provider().get_credentials().await.unwrap();
+    assert!(format!("{:?}", credentials).contains("provider_name: \"STSCredentialsProvider\""));
+
+    let provider = sts.builder().provider_name("billing-role").build().unwrap();
+    let credentials = provider.get_credentials().await.unwrap();
+    assert!(format!("{:?}", credentials).contains("provider_name: \"billing-role\""));
+}
+
+#[test]
+fn provider_names_must_not_be_empty() {
+    let error = StubSts::new()
+        .builder()
+        .provider_name("")
+        .build()
+        .unwrap_err();
+    assert!(
+        matches!(error, BuildError::EmptyProviderName),
+        "{:?}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn reads_with_a_past_deadline_fail_right_away() {
+    let sts = StubSts::new();
+    let provider = sts.provider();
+    let error = provider
+        .get_credentials_with_deadline(Instant::now())
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("timed out"), "{}", error);
+    assert_eq!(sts.calls(), 0);
+}
+
+#[tokio::test]
+async fn reads_give_up_at_the_deadline() {
+    let sts = StubSts::new().delay(Duration::from_millis(300));
+    let provider = sts.provider();
+    let deadline = Instant::now() + Duration::from_millis(50);
+    let error = provider
+        .get_credentials_with_deadline(deadline)
+        .await
+        .unwrap_err();
+    assert!(error.to_string().contains("timed out"), "{}", error);
+    assert!(Instant::now() < deadline + Duration::from_millis(100));
+    // The abandoned refresh doesn't fill the cache
+    assert_eq!(provider.current_access_key_id().await, None);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let credentials = provider
+        .get_credentials_with_deadline(deadline)
+        .await
+        .unwrap();
+    assert_eq!(credentials.access_key_id(), access_key_id(2));
+}
+
+#[tokio::test]
+async fn issued_at_is_the_local_time_of_the_call() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    let provider = sts.provider();
+    assert_eq!(provider.issued_at().await, None);
+
+    let requested = clock.now();
+    provider.get_credentials().await.unwrap();
+    assert_eq!(provider.issued_at().await, Some(requested));
+    assert_eq!(provider.granted_lifetime().await, Some(DEFAULT_LIFETIME));
+    // It doesn't change while the credentials are cached
+    clock.advance(Duration::from_secs(60));
+    provider.get_credentials().await.unwrap();
+    assert_eq!(provider.issued_at().await, Some(requested));
+
+    provider.force_rotate().await.unwrap();
+    assert_eq!(
+        provider.issued_at().await,
+        Some(requested + Duration::from_secs(60))
+    );
+}
+
+#[tokio::test]
+async fn the_app_name_is_sent_in_the_user_agent() {
+    let sts = StubSts::new();
+    let provider = sts.builder().app_name("billing").build().unwrap();
+    provider.get_credentials().await.unwrap();
+    let headers = sts.last().headers;
+    let user_agent = headers["x-amz-user-agent"].to_str().unwrap();
+    assert!(user_agent.contains("app/billing"), "{}", user_agent);
+}
+
+#[test]
+fn invalid_app_names_are_rejected() {
+    let error = StubSts::new()
+        .builder()
+        .app_name("billing service")
+        .build()
+        .unwrap_err();
+    assert!(
+        matches!(error, BuildError::InvalidAppName(ref name) if name == "billing service"),
+        "{:?}",
+        error
+    );
+}
+
+#[tokio::test]
+async fn passthrough_returns_the_source_credentials() {
+    let sts = StubSts::new();
+    let provider = sts.builder().passthrough(true).build().unwrap();
+    let credentials = provider.get_credentials().await.unwrap();
+    assert_eq!(credentials.access_key_id(), testing::SOURCE_ACCESS_KEY_ID);
+    assert_eq!(sts.calls(), 0);
+
+    let provider = sts
+        .builder()
+        .source_profile(testing::PROFILE)
+        .passthrough(true)
+        .build()
+        .unwrap();
+    let credentials = provider.get_credentials().await.unwrap();
+    assert_eq!(credentials.access_key_id(), testing::PROFILE_ACCESS_KEY_ID);
+    assert_eq!(sts.calls(), 0);
+}
+
+#[tokio::test]
+async fn errors_name_the_region_and_endpoint_of_the_call() {
+    let sts = StubSts::new();
+    sts.reply([Reply::invalid("rejected")]);
+    let error = sts.provider().get_credentials().await.unwrap_err();
+    let region = match cfg!(feature = "sts-global-endpoint") {
+        true => "aws-global",
+        false => testing::REGION,
+    };
+    let target = format!(
+        "AssumeRole failed (region {}, the default endpoint of the SDK)",
+        region
+    );
+    assert!(
+        error_chain(&error).contains(&target),
+        "{}",
+        error_chain(&error)
+    );
+}
+
+/// Returns the number of the call that returned the credentials with the given key id.
+fn call_of(access_key_id: &str) -> usize {
+    access_key_id
+        .trim_start_matches("ASIASTUB")
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_reads_never_return_replaced_credentials() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock)).delay(Duration::from_millis(20));
+    let provider = Arc::new(sts.provider());
+    provider.get_credentials().await.unwrap();
+    // Number of the oldest call whose credentials may still be returned
+    let oldest = Arc::new(AtomicUsize::new(1));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let (provider, oldest, done) = (provider.clone(), oldest.clone(), done.clone());
+            tokio::spawn(async move {
+                let mut reads = 0;
+                while !done.load(Ordering::SeqCst) {
+                    let floor = oldest.load(Ordering::SeqCst);
+                    let credentials = provider.get_credentials().await.unwrap();
+                    let call = call_of(credentials.access_key_id());
+                    assert!(call >= floor, "read call {} after call {}", call, floor);
+                    reads += 1;
+                    tokio::task::yield_now().await;
+                }
+                reads
+            })
+        })
+        .collect();
+
+    for round in 0..6 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let current = call_of(&provider.current_access_key_id().await.unwrap());
+        if round % 2 == 0 {
+            let (_, new) = provider.force_rotate().await.unwrap();
+            oldest.store(call_of(new.access_key_id()), Ordering::SeqCst);
+        } else {
+            // The cached credentials become stale, so reads must wait for newer ones
+            clock.advance(DEFAULT_LIFETIME);
+            oldest.store(current + 1, Ordering::SeqCst);
+        }
+    }
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    done.store(true, Ordering::SeqCst);
+    for reader in readers {
+        assert!(reader.await.unwrap() > 0);
+    }
+    // Each expiry was refreshed once, however many reads found the credentials stale
+    assert_eq!(sts.calls(), 7);
+}
+
+#[tokio::test]
+async fn providers_sharing_a_rate_limiter_wait_for_each_other() {
+    let sts = StubSts::new();
+    let limiter = RateLimiter::new(std::num::NonZeroU32::new(2).unwrap());
+    let sleep = Arc::new(testing::RecordingSleep::default());
+    let first = sts
+        .builder()
+        .rate_limiter(limiter.clone())
+        .sleep_impl(sleep.clone())
+        .build()
+        .unwrap();
+    let second = sts
+        .builder()
+        .rate_limiter(limiter)
+        .sleep_impl(sleep.clone())
+        .build()
+        .unwrap();
+
+    // The burst is used up by the first provider
+    first.get_credentials().await.unwrap();
+    first.force_rotate().await.unwrap();
+    assert!(sleep.durations().is_empty(), "{:?}", sleep.durations());
+    second.get_credentials().await.unwrap();
+    let durations = sleep.durations();
+    assert_eq!(durations.len(), 1, "{:?}", durations);
+    // Half a second per call, minus the time it took to make the first two
+    assert!(
+        durations[0] <= Duration::from_millis(500),
+        "{:?}",
+        durations
+    );
+    assert!(durations[0] > Duration::from_millis(300), "{:?}", durations);
+    assert_eq!(sts.calls(), 3);
+}
+
+#[tokio::test]
+async fn the_call_cap_serves_stale_credentials_then_fails() {
+    let mut clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    let provider = sts.builder().max_lifetime_calls(2).build().unwrap();
+    provider.get_credentials().await.unwrap();
+    provider.force_rotate().await.unwrap();
+    let error = provider.force_rotate().await.unwrap_err();
+    assert!(error.to_string().contains("maximum of 2"), "{}", error);
+
+    // Stale, but not expired yet
+    clock.advance(DEFAULT_LIFETIME - Duration::from_secs(30));
+    let credentials = provider.get_credentials().await.unwrap();
+    assert_eq!(credentials.access_key_id(), access_key_id(2));
+    clock.advance(Duration::from_secs(60));
+    let error = provider.get_credentials().await.unwrap_err();
+    assert!(error.to_string().contains("maximum of 2"), "{}", error);
+    assert_eq!(sts.calls(), 2);
+}
+
+#[tokio::test]
+async fn the_sts_client_is_that_of_the_provider() {
+    let sts = StubSts::new();
+    let provider = sts.provider();
+    let client = provider.sts_client().await.unwrap();
+    // The stub doesn't answer GetCallerIdentity properly, only the request matters
+    let _ = client.get_caller_identity().send().await;
+    let request = sts.last();
+    assert_eq!(request.param("Action").unwrap(), "GetCallerIdentity");
+    assert_eq!(request.signing_key_id(), testing::SOURCE_ACCESS_KEY_ID);
+
+    provider.get_credentials().await.unwrap();
+    assert_eq!(sts.calls(), 2);
+}
+
+#[tokio::test]
+async fn the_refresh_clock_skew_policy_requests_new_credentials_once() {
+    let (logs, _guard) = testing::capture_logs();
+    let clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    sts.reply([
+        issued_ahead_of(&clock, Duration::from_secs(60)),
+        issued_ahead_of(&clock, Duration::from_secs(60)),
+        issued_ahead_of(&clock, Duration::from_secs(60)),
+    ]);
+    let provider = sts
+        .builder()
+        .clock_skew_policy(ClockSkewPolicy::Refresh)
+        .build()
+        .unwrap();
+    let credentials = provider.get_credentials().await.unwrap();
+    // The second credentials are served even though they were also issued in the future
+    assert_eq!(credentials.access_key_id(), access_key_id(2));
+    assert_eq!(sts.calls(), 2);
+    assert_eq!(logs.matching("Returning them anyway").len(), 1);
+
+    // The new credentials count against the cap on the number of calls
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    sts.reply([issued_ahead_of(&clock, Duration::from_secs(60))]);
+    let provider = sts
+        .builder()
+        .clock_skew_policy(ClockSkewPolicy::Refresh)
+        .max_lifetime_calls(1)
+        .build()
+        .unwrap();
+    let error = provider.get_credentials().await.unwrap_err();
+    assert!(error.to_string().contains("maximum of 1"), "{}", error);
+    assert_eq!(sts.calls(), 1);
+}
+
+#[tokio::test]
+async fn the_serve_clock_skew_policy_neither_waits_nor_refreshes() {
+    let clock = manual_time();
+    let sts = StubSts::with_time(TimeSource::manual(&clock));
+    sts.reply([issued_ahead_of(&clock, Duration::from_secs(1))]);
+    let sleep = Arc::new(testing::RecordingSleep::default());
+    let provider = sts
+        .builder()
+        .clock_skew_policy(ClockSkewPolicy::Serve)
+        .sleep_impl(sleep.clone())
+        .build()
+        .unwrap();
+    let credentials = provider.get_credentials().await.unwrap();
+    assert_eq!(credentials.access_key_id(), access_key_id(1));
+    assert!(sleep.durations().is_empty(), "{:?}", sleep.durations());
+    assert_eq!(sts.calls(), 1);
+}
+
+#[tokio::test]
+async fn web_identities_assume_the_role_with_the_current_token() {
+    let dir = std::env::temp_dir().join(format!("sts-caching-provider-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("web-identity");
+    std::fs::write(&path, "first-token").unwrap();
+    let sts = StubSts::new();
+    let provider = sts
+        .builder()
+        .web_identity(FileTokenProvider::new(&path))
+        .build()
+        .unwrap();
+
+    let credentials = provider.get_credentials().await.unwrap();
+    assert_eq!(credentials.access_key_id(), access_key_id(1));
+    let request = sts.last();
+    assert_eq!(
+        request.param("Action").unwrap(),
+        "AssumeRoleWithWebIdentity"
+    );
+    assert_eq!(request.param("WebIdentityToken").unwrap(), "first-token");
+    assert_eq!(request.param("RoleArn").unwrap(), testing::ROLE_ARN);
+    // The call is authenticated by the token only
+    assert!(!request.headers.contains_key("authorization"));
+
+    // The token is read again for each call
+    std::fs::write(&path, "second-token").unwrap();
+    provider.force_rotate().await.unwrap();
+    assert_eq!(
+        sts.last().param("WebIdentityToken").unwrap(),
+        "second-token"
+    );
+}
+
+/// Parameters of `AssumeRole` that are only sent when set to a non-empty value.
+const OPTIONAL_PARAMS: [&str; 5] = [
+    "ExternalId",
+    "SourceIdentity",
+    "RoleSessionName",
+    "Tags.member.1.Key",
+    "Policy",
+];
+
+#[tokio::test]
+async fn optional_parameters_are_sent_when_set() {
+    let sts = StubSts::new();
+    let provider = sts
+        .builder()
+        .external_id("external")
+        .source_identity("identity")
+        .session_name("session")
+        .tag("team", "billing")
+        .build()
+        .unwrap()
+        .with_overrides(Overrides::default().policy("{}"));
+    provider.get_credentials().await.unwrap();
+    let request = sts.last();
+    for (param, value) in OPTIONAL_PARAMS
+        .iter()
+        .zip(["external", "identity", "session", "team", "{}"])
+    {
+        assert_eq!(request.param(param).as_deref(), Some(value), "{}", param);
+    }
+    assert_eq!(
+        request.param("Tags.member.1.Value").as_deref(),
+        Some("billing")
+    );
+}
+
+#[tokio::test]
+async fn optional_parameters_are_omitted_when_unset_or_empty() {
+    let sts = StubSts::new();
+    sts.builder()
+        .set_session_name(None)
+        .build()
+        .unwrap()
+        .get_credentials()
+        .await
+        .unwrap();
+    let unset = sts.last();
+
+    sts.builder()
+        .external_id("")
+        .source_identity("")
+        .session_name("")
+        .tags(HashMap::new())
+        .build()
+        .unwrap()
+        .with_overrides(Overrides::default().policy(""))
+        .get_credentials()
+        .await
+        .unwrap();
+    let empty = sts.last();
+
+    for request in [unset, empty] {
+        for param in OPTIONAL_PARAMS {
+            assert_eq!(request.param(param), None, "{} in {}", param, request.body);
+        }
+    }
+}
+
+fn params_with_external_id(external_id: Option<&str>) -> AssumeRoleParams {
+    AssumeRoleParams {
+        role_arn: testing::ROLE_ARN.to_string(),
+        external_id: external_id.map(str::to_string),
+        source_identity: None,
+        session_name: None,
+        session_duration: None,
+        tags: Vec::new(),
+        policy: None,
+    }
+}
+
+#[test]
+fn access_denied_hints_follow_the_message_and_parameters() {
+    let params = params_with_external_id(Some("external"));
+    let hint = |message| access_denied_hint(message, &params);
+    assert!(hint("not authorized to perform: sts:TagSession").contains("allow sts:TagSession"));
+    assert!(hint("not authorized to perform: sts:SetSourceIdentity")
+        .contains("allow sts:SetSourceIdentity"));
+    assert!(hint("aws:MultiFactorAuthPresent is false").contains("require MFA"));
+    assert!(hint("MFA required").contains("require MFA"));
+    assert!(hint("not authorized to perform: sts:AssumeRole")
+        .contains("external id matches the sts:ExternalId condition"));
+
+    for external_id in [None, Some("")] {
+        let params = params_with_external_id(external_id);
+        let hint = access_denied_hint("not authorized to perform: sts:AssumeRole", &params);
+        assert!(hint.contains("set the external id"), "{}", hint);
+    }
+}
+
+#[tokio::test]
+async fn access_denied_errors_carry_the_hint() {
+    let sts = StubSts::new();
+    sts.reply([Reply::access_denied(
+        "User is not authorized to perform: sts:TagSession",
+    )]);
+    let provider = sts.builder().tag("team", "billing").build().unwrap();
+    let error = provider.get_credentials().await.unwrap_err();
+    let chain = error_chain(&error);
+    assert!(chain.contains("AssumeRole was denied"), "{}", chain);
+    assert!(chain.contains("allow sts:TagSession"), "{}", chain);
+}